@@ -0,0 +1,27 @@
+use std::hint::black_box;
+use std::time::Instant;
+
+use nac::Expression;
+
+const TERMS: usize = 10_000;
+const RUNS: u32 = 20;
+
+fn main() {
+    let ops = ["+", "-", "*", "/"];
+
+    let mut input = String::from("1");
+    for n in 1..TERMS {
+        input.push_str(&format!(" {} {}", ops[n % ops.len()], n % 97 + 1));
+    }
+
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        let root = Expression::root(black_box(&input)).unwrap();
+        black_box(root.eval().unwrap());
+    }
+
+    println!(
+        "{TERMS} terms: {:?} per parse and eval",
+        start.elapsed() / RUNS
+    );
+}
@@ -0,0 +1,25 @@
+#ifndef NAC_H
+#define NAC_H
+
+/* Return codes of nac_eval. */
+#define NAC_OK 0
+#define NAC_NULL_POINTER 1
+#define NAC_INVALID_UTF8 2
+#define NAC_PARSE_ERROR 3
+#define NAC_EVAL_ERROR 4
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/*
+ * Evaluates the NUL terminated expression in input and writes the result to
+ * *out, returning NAC_OK. On any other return *out is left alone.
+ */
+int nac_eval(const char *input, double *out);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
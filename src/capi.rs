@@ -0,0 +1,76 @@
+// A C entry point, declared for C and C++ in include/nac.h. Build with
+// `cargo rustc --release --features capi --crate-type cdylib` (or
+// `staticlib`) to get something to link against.
+
+use std::ffi::{c_char, CStr};
+
+use crate::Expression;
+
+pub const NAC_OK: i32 = 0;
+pub const NAC_NULL_POINTER: i32 = 1;
+pub const NAC_INVALID_UTF8: i32 = 2;
+pub const NAC_PARSE_ERROR: i32 = 3;
+pub const NAC_EVAL_ERROR: i32 = 4;
+
+/// Evaluates `input` and writes the result to `out`, returning [`NAC_OK`].
+/// Otherwise it returns one of the other codes and leaves `out` alone.
+///
+/// ```
+/// use std::ffi::CString;
+///
+/// use nac::{nac_eval, NAC_EVAL_ERROR, NAC_INVALID_UTF8, NAC_NULL_POINTER, NAC_OK, NAC_PARSE_ERROR};
+///
+/// let eval = |input: &[u8], out: &mut f64| {
+///     let input = CString::new(input).unwrap();
+///     unsafe { nac_eval(input.as_ptr(), out) }
+/// };
+///
+/// let mut out = 0.0;
+/// assert_eq!(eval(b"2 + 2", &mut out), NAC_OK);
+/// assert_eq!(out, 4.0);
+/// assert_eq!(eval(b"2 +", &mut out), NAC_PARSE_ERROR);
+/// assert_eq!(eval(b"1 / 0", &mut out), NAC_EVAL_ERROR);
+/// assert_eq!(eval(b"\xff", &mut out), NAC_INVALID_UTF8);
+/// assert_eq!(out, 4.0);
+///
+/// assert_eq!(unsafe { nac_eval(std::ptr::null(), &mut out) }, NAC_NULL_POINTER);
+/// let input = CString::new("1").unwrap();
+/// assert_eq!(unsafe { nac_eval(input.as_ptr(), std::ptr::null_mut()) }, NAC_NULL_POINTER);
+///
+/// // The header has to agree with all of the above.
+/// let header = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/include/nac.h"));
+/// assert!(header.contains("int nac_eval(const char *input, double *out);"));
+/// for (name, code) in [
+///     ("NAC_OK", NAC_OK),
+///     ("NAC_NULL_POINTER", NAC_NULL_POINTER),
+///     ("NAC_INVALID_UTF8", NAC_INVALID_UTF8),
+///     ("NAC_PARSE_ERROR", NAC_PARSE_ERROR),
+///     ("NAC_EVAL_ERROR", NAC_EVAL_ERROR),
+/// ] {
+///     assert!(header.contains(&format!("#define {name} {code}\n")));
+/// }
+/// ```
+///
+/// # Safety
+///
+/// `input` has to be null or point to a NUL terminated string, and `out`
+/// has to be null or point to somewhere a `double` can be written.
+#[no_mangle]
+pub unsafe extern "C" fn nac_eval(input: *const c_char, out: *mut f64) -> i32 {
+    if input.is_null() || out.is_null() {
+        return NAC_NULL_POINTER;
+    }
+
+    let Ok(input) = CStr::from_ptr(input).to_str() else {
+        return NAC_INVALID_UTF8;
+    };
+    let Ok(root) = Expression::root(input) else {
+        return NAC_PARSE_ERROR;
+    };
+    let Ok(value) = root.eval() else {
+        return NAC_EVAL_ERROR;
+    };
+
+    *out = value;
+    NAC_OK
+}
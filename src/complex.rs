@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::{
+    call_function, constant, factorial, AngleMode, CalcError, EvalOptions, Expression, OpParams,
+    Result,
+};
+
+/// A complex number, as `eval_complex` gives it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64,
+}
+
+impl Complex {
+    pub const I: Complex = Complex { re: 0.0, im: 1.0 };
+
+    pub fn new(re: f64, im: f64) -> Complex {
+        Complex { re, im }
+    }
+
+    pub fn is_real(&self) -> bool {
+        self.im == 0.0
+    }
+
+    pub fn abs(&self) -> f64 {
+        self.re.hypot(self.im)
+    }
+
+    pub fn arg(&self) -> f64 {
+        self.im.atan2(self.re)
+    }
+
+    fn add(self, other: Complex) -> Complex {
+        Complex::new(self.re + other.re, self.im + other.im)
+    }
+
+    fn sub(self, other: Complex) -> Complex {
+        Complex::new(self.re - other.re, self.im - other.im)
+    }
+
+    fn mul(self, other: Complex) -> Complex {
+        Complex::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+
+    fn div(self, other: Complex) -> Complex {
+        let scale = other.re * other.re + other.im * other.im;
+        Complex::new(
+            (self.re * other.re + self.im * other.im) / scale,
+            (self.im * other.re - self.re * other.im) / scale,
+        )
+    }
+
+    fn ln(self) -> Complex {
+        Complex::new(self.abs().ln(), self.arg())
+    }
+
+    fn exp(self) -> Complex {
+        let scale = self.re.exp();
+        Complex::new(scale * self.im.cos(), scale * self.im.sin())
+    }
+
+    // The principal root, worked out without going through `ln` so that
+    // `sqrt(-1)` is exactly `i`.
+    fn sqrt(self) -> Complex {
+        let abs = self.abs();
+        let re = ((abs + self.re) / 2.0).sqrt();
+        let im = ((abs - self.re) / 2.0).sqrt();
+        Complex::new(re, if self.im < 0.0 { -im } else { im })
+    }
+
+    fn pow(self, exponent: Complex) -> Complex {
+        if exponent == Complex::new(0.5, 0.0) {
+            return self.sqrt();
+        }
+
+        // Whole powers by repeated multiplication, so `i^2` is exactly -1.
+        if exponent.is_real() && exponent.re.fract() == 0.0 && exponent.re.abs() <= 64.0 {
+            let mut result = Complex::new(1.0, 0.0);
+            for _ in 0..exponent.re.abs() as u32 {
+                result = result.mul(self);
+            }
+            return match exponent.re < 0.0 {
+                true => Complex::new(1.0, 0.0).div(result),
+                false => result,
+            };
+        }
+
+        if self == Complex::default() {
+            return Complex::default();
+        }
+
+        exponent.mul(self.ln()).exp()
+    }
+}
+
+impl From<f64> for Complex {
+    fn from(re: f64) -> Complex {
+        Complex::new(re, 0.0)
+    }
+}
+
+impl fmt::Display for Complex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let im = match self.im.abs() {
+            1.0 => String::new(),
+            im => im.to_string(),
+        };
+
+        match (self.re, self.im) {
+            (re, 0.0) => write!(f, "{re}"),
+            (0.0, b) if b < 0.0 => write!(f, "-{im}i"),
+            (0.0, _) => write!(f, "{im}i"),
+            (re, b) if b < 0.0 => write!(f, "{re}-{im}i"),
+            (re, _) => write!(f, "{re}+{im}i"),
+        }
+    }
+}
+
+impl Expression {
+    /// Evaluates over the complex numbers, with `i` as the imaginary unit
+    /// unless the expression assigns it. Wherever the ordinary `eval` has a
+    /// real answer this gives the same one, so `3 ~ -8` is still -2, and
+    /// where it fails for want of one this goes on: `sqrt(-1)` is `i` and
+    /// `(-1)^0.5` is too.
+    ///
+    /// ```
+    /// use nac::{Complex, Expression};
+    ///
+    /// let eval = |input| Expression::root(input).unwrap().eval_complex().unwrap();
+    ///
+    /// assert_eq!(eval("2 + 3i"), Complex::new(2.0, 3.0));
+    /// assert_eq!(eval("sqrt(-1)"), Complex::I);
+    /// assert_eq!(eval("2 ~ -1"), Complex::I);
+    /// assert_eq!(eval("i^2"), Complex::from(-1.0));
+    /// assert_eq!(eval("(1 + 2i) * (3 - i)"), Complex::new(5.0, 5.0));
+    /// assert_eq!(eval("(5 + 5i) / (3 - i)"), Complex::new(1.0, 2.0));
+    /// assert_eq!(eval("|3 + 4i|"), Complex::from(5.0));
+    /// assert_eq!(eval("3 ~ -8"), Complex::from(-2.0));
+    /// assert_eq!(eval("2 - i").to_string(), "2-i");
+    /// assert_eq!(eval("-4i / 2").to_string(), "-2i");
+    ///
+    /// let rotated = eval("e^(i * pi)");
+    /// assert!((rotated.re + 1.0).abs() < 1e-15 && rotated.im.abs() < 1e-15);
+    ///
+    /// assert!(Expression::root("i < 1").unwrap().eval_complex().is_err());
+    /// ```
+    pub fn eval_complex(&self) -> Result<Complex> {
+        self.eval_complex_with(&HashMap::new(), AngleMode::default())
+    }
+
+    /// Like [`eval_complex`](Expression::eval_complex), with the real
+    /// variables in `env` to draw on and angles in `mode`.
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    ///
+    /// use nac::{AngleMode, Complex, Expression};
+    ///
+    /// let exp = Expression::root("sin(x) + sqrt(-1)").unwrap();
+    /// let env = HashMap::from([("x".to_string(), 90.0)]);
+    /// assert_eq!(exp.eval_complex_with(&env, AngleMode::Degrees), Ok(Complex::new(1.0, 1.0)));
+    /// ```
+    pub fn eval_complex_with(
+        &self,
+        env: &HashMap<String, f64>,
+        mode: AngleMode,
+    ) -> Result<Complex> {
+        let names: Vec<String> = self
+            .free_variables()
+            .into_iter()
+            .filter(|name| *name != "i" && !env.contains_key(*name))
+            .map(str::to_string)
+            .collect();
+        if !names.is_empty() {
+            return Err(CalcError::UnboundVariables { names });
+        }
+
+        self.eval_complex_with_env(&mut env.clone(), mode)
+    }
+
+    /// Like [`eval_complex_with`](Expression::eval_complex_with), keeping
+    /// what the expression assigns in `env` the way
+    /// [`eval_with_env_and_mode`](Expression::eval_with_env_and_mode) does.
+    /// A variable given a value that isn't real is taken out of `env`, which
+    /// only holds reals.
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    ///
+    /// use nac::{AngleMode, Complex, Expression};
+    ///
+    /// let exp = Expression::root("a = i * i; b = 2i; sqrt(a)").unwrap();
+    /// let mut env = HashMap::from([("b".to_string(), 1.0)]);
+    /// assert_eq!(exp.eval_complex_with_env(&mut env, AngleMode::Radians), Ok(Complex::I));
+    /// assert_eq!(env, HashMap::from([("a".to_string(), -1.0)]));
+    /// ```
+    pub fn eval_complex_with_env(
+        &self,
+        env: &mut HashMap<String, f64>,
+        mode: AngleMode,
+    ) -> Result<Complex> {
+        let mut complex_env = env
+            .iter()
+            .map(|(name, value)| (name.clone(), Complex::from(*value)))
+            .collect();
+        let value = self.evaluate_complex(&mut complex_env, mode);
+
+        for (name, assigned) in complex_env {
+            if assigned.is_real() {
+                env.insert(name, assigned.re);
+            } else {
+                env.remove(&name);
+            }
+        }
+        value
+    }
+
+    fn evaluate_complex(
+        &self,
+        env: &mut HashMap<String, Complex>,
+        mode: AngleMode,
+    ) -> Result<Complex> {
+        let real_only = |values: &[Complex]| match values.iter().find(|value| !value.is_real()) {
+            Some(value) => Err(CalcError::Math {
+                message: format!("{} needs real operands, got {value}", self.label()),
+            }),
+            None => Ok(()),
+        };
+
+        if let Some(OpParams {
+            lhs: Some(lhs),
+            rhs: Some(rhs),
+        }) = self.params()
+        {
+            if let Expression::Assign(_) = self {
+                let Expression::Var(name) = lhs.as_ref() else {
+                    return Err(CalcError::NotAssignable {
+                        target: lhs.to_string(),
+                    });
+                };
+                let value = rhs.evaluate_complex(env, mode)?;
+                env.insert(name.clone(), value);
+                return Ok(value);
+            }
+
+            let lhs = lhs.evaluate_complex(env, mode)?;
+            let rhs = match (self, rhs.as_ref()) {
+                (Expression::Add(_) | Expression::Sub(_), Expression::Percent(inner)) => {
+                    let percent = inner.evaluate_complex(env, mode)?;
+                    lhs.mul(percent).div(Complex::from(100.0))
+                }
+                _ => rhs.evaluate_complex(env, mode)?,
+            };
+
+            if lhs.is_real() && rhs.is_real() {
+                let real = self.with_real_operands(lhs.re, rhs.re, mode);
+                match real {
+                    Err(CalcError::NoRealResult { .. }) => {}
+                    _ => return real.map(Complex::from),
+                }
+            }
+
+            return match self {
+                Expression::Add(_) => Ok(lhs.add(rhs)),
+                Expression::Sub(_) => Ok(lhs.sub(rhs)),
+                Expression::Mul(_) => Ok(lhs.mul(rhs)),
+                Expression::Div(_) if rhs == Complex::default() => Err(CalcError::DivByZero {
+                    expression: self.to_string(),
+                }),
+                Expression::Div(_) => Ok(lhs.div(rhs)),
+                Expression::Pow(_) => Ok(lhs.pow(rhs)),
+                Expression::Root(_) => Ok(rhs.pow(Complex::from(1.0).div(lhs))),
+                _ => {
+                    let value = if lhs.is_real() { rhs } else { lhs };
+                    Err(CalcError::Math {
+                        message: format!("{} needs real operands, got {value}", self.label()),
+                    })
+                }
+            };
+        }
+
+        match self {
+            Expression::Unit(_) => self
+                .evaluate(&mut HashMap::new(), mode, EvalOptions::default())
+                .map(Complex::from),
+            Expression::Var(name) => match env.get(name) {
+                Some(value) => Ok(*value),
+                None if name == "i" => Ok(Complex::I),
+                None => constant(name)
+                    .map(Complex::from)
+                    .ok_or_else(|| CalcError::UnknownVariable { name: name.clone() }),
+            },
+            // Subtracted rather than negated: a -0 imaginary part would put
+            // the negative reals on the wrong side of the branch cut.
+            Expression::Neg(Some(inner)) => {
+                Ok(Complex::default().sub(inner.evaluate_complex(env, mode)?))
+            }
+            Expression::Pos(Some(inner)) => inner.evaluate_complex(env, mode),
+            Expression::Not(Some(inner)) => {
+                let value = inner.evaluate_complex(env, mode)?;
+                real_only(&[value])?;
+                Ok(Complex::from(f64::from(value.re == 0.0)))
+            }
+            Expression::Abs(inner) => Ok(Complex::from(inner.evaluate_complex(env, mode)?.abs())),
+            Expression::Percent(inner) => {
+                Ok(inner.evaluate_complex(env, mode)?.div(Complex::from(100.0)))
+            }
+            Expression::Fact(inner) => {
+                let value = inner.evaluate_complex(env, mode)?;
+                real_only(&[value])?;
+                factorial(value.re).map(Complex::from)
+            }
+            Expression::Call { name, args } => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.evaluate_complex(env, mode))
+                    .collect::<Result<Vec<Complex>>>()?;
+                match (name.as_str(), args.as_slice()) {
+                    ("sqrt", [x]) if x.is_real() && x.re >= 0.0 => Ok(Complex::from(x.re.sqrt())),
+                    ("sqrt", [x]) => Ok(x.sqrt()),
+                    ("ln", [x]) if x.is_real() && x.re > 0.0 => Ok(Complex::from(x.re.ln())),
+                    ("ln", [x]) if *x != Complex::default() => Ok(x.ln()),
+                    _ => {
+                        real_only(&args)?;
+                        let args: Vec<f64> = args.iter().map(|arg| arg.re).collect();
+                        call_function(name, &args, mode).map(Complex::from)
+                    }
+                }
+            }
+            Expression::Group(group) => match group.body.as_slice() {
+                [exp] => exp.evaluate_complex(env, mode),
+                [] => Err(CalcError::EmptyExpression),
+                _ => Err(CalcError::Unresolved {
+                    span: Some(group.spans[1]),
+                }),
+            },
+            Expression::Seq(statements) => {
+                let mut value = Complex::default();
+                for statement in statements {
+                    value = statement.evaluate_complex(env, mode)?;
+                }
+                Ok(value)
+            }
+            _ => Err(CalcError::Unresolved { span: None }),
+        }
+    }
+
+    // Applies this binary operator to two plain numbers, the way `eval` would.
+    fn with_real_operands(&self, lhs: f64, rhs: f64, mode: AngleMode) -> Result<f64> {
+        let params = OpParams::new(Expression::number(lhs), Expression::number(rhs));
+        let exp = self
+            .with_params(params)
+            .ok_or(CalcError::Unresolved { span: None })?;
+        exp.evaluate(&mut HashMap::new(), mode, EvalOptions::default())
+    }
+}
@@ -0,0 +1,153 @@
+use std::fmt;
+
+use crate::{Expression, Rational, Result};
+
+/// A base 10 number: `mantissa` over `10^scale`, kept without trailing zeros.
+/// `0.1 + 0.2` is exactly 0.3 here, where a float gets
+/// 0.30000000000000004.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decimal {
+    mantissa: i128,
+    scale: u32,
+}
+
+impl Decimal {
+    /// As many places as `eval_decimal` keeps after the point when a
+    /// division doesn't come out even.
+    pub const MAX_SCALE: u32 = 28;
+
+    /// `mantissa / 10^scale`, or `None` if that still needs more than
+    /// [`MAX_SCALE`](Decimal::MAX_SCALE) places once trailing zeros are
+    /// dropped.
+    ///
+    /// ```
+    /// use nac::Decimal;
+    ///
+    /// assert_eq!(Decimal::new(250, 2).unwrap().to_string(), "2.5");
+    /// assert_eq!(Decimal::new(1, 29), None);
+    /// ```
+    pub fn new(mantissa: i128, scale: u32) -> Option<Decimal> {
+        let decimal = Decimal::trimmed(mantissa, scale);
+        (decimal.scale <= Decimal::MAX_SCALE).then_some(decimal)
+    }
+
+    fn trimmed(mantissa: i128, scale: u32) -> Decimal {
+        let (mut mantissa, mut scale) = (mantissa, scale);
+        while scale > 0 && mantissa % 10 == 0 {
+            mantissa /= 10;
+            scale -= 1;
+        }
+
+        Decimal { mantissa, scale }
+    }
+
+    pub fn mantissa(&self) -> i128 {
+        self.mantissa
+    }
+
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        self.to_string().parse().unwrap_or(f64::NAN)
+    }
+
+    /// Rounds to `places` after the point, halves away from zero, so
+    /// `2.675` to two places is 2.68.
+    pub fn round_dp(&self, places: u32) -> Decimal {
+        if self.scale <= places {
+            return *self;
+        }
+
+        let divisor = 10i128.pow(self.scale - places);
+        let (quotient, remainder) = (self.mantissa / divisor, self.mantissa % divisor);
+        let rounded = match remainder.unsigned_abs() * 2 >= divisor.unsigned_abs() {
+            true => quotient + self.mantissa.signum(),
+            false => quotient,
+        };
+
+        Decimal::trimmed(rounded, places)
+    }
+
+    // Long division, one digit at a time so nothing outgrows `i128` before
+    // the mantissa itself would. Stops at `MAX_SCALE` places, or sooner when
+    // the integer part leaves no room for more.
+    fn from_rational(value: Rational) -> Decimal {
+        let (numer, denom) = (value.numer(), value.denom());
+        let (mut mantissa, mut remainder) = (numer / denom, numer % denom);
+        let mut scale = 0;
+
+        while remainder != 0 && scale < Decimal::MAX_SCALE {
+            let Some(shifted) = mantissa.checked_mul(10) else {
+                break;
+            };
+            let Some(carried) = remainder.checked_mul(10) else {
+                break;
+            };
+            let Some(next) = shifted.checked_add(carried / denom) else {
+                break;
+            };
+            mantissa = next;
+            remainder = carried % denom;
+            scale += 1;
+        }
+
+        if remainder.unsigned_abs() * 2 >= denom.unsigned_abs() {
+            mantissa = mantissa.saturating_add(numer.signum());
+        }
+
+        Decimal::trimmed(mantissa, scale)
+    }
+}
+
+/// A precision pads or rounds to that many places, `{:.2}` for money.
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (value, places) = match f.precision() {
+            Some(places) => (self.round_dp(places as u32), places),
+            None => (*self, self.scale as usize),
+        };
+
+        let digits = format!(
+            "{:0>width$}",
+            value.mantissa.unsigned_abs(),
+            width = value.scale as usize + 1
+        );
+        let (int, frac) = digits.split_at(digits.len() - value.scale as usize);
+        let sign = if value.mantissa < 0 { "-" } else { "" };
+
+        match places {
+            0 => write!(f, "{sign}{int}"),
+            _ => write!(f, "{sign}{int}.{frac:0<places$}"),
+        }
+    }
+}
+
+impl Expression {
+    /// Evaluates in base 10, for sums of money that have to come out
+    /// exactly. The work is done in exact fractions, as by
+    /// [`eval_rational`](Expression::eval_rational), so the same things are
+    /// allowed; an answer that doesn't end, like `1/3`, is rounded to
+    /// [`Decimal::MAX_SCALE`] places.
+    ///
+    /// ```
+    /// use nac::{CalcError, Decimal, Expression};
+    ///
+    /// let decimal = |input| Expression::root(input).unwrap().eval_decimal();
+    ///
+    /// assert_eq!(decimal("0.1 + 0.2"), Ok(Decimal::new(3, 1).unwrap()));
+    /// assert_eq!(decimal("19.99 * 3 - 10%").unwrap().to_string(), "53.973");
+    /// assert_eq!(decimal("1.05^3").unwrap().to_string(), "1.157625");
+    /// assert_eq!(decimal("10.5 % 3").unwrap().to_string(), "1.5");
+    /// assert_eq!(decimal("2/3").unwrap().to_string(), "0.6666666666666666666666666667");
+    ///
+    /// assert_eq!(format!("{:.2}", decimal("2.675").unwrap()), "2.68");
+    /// assert_eq!(format!("{:.2}", decimal("-7").unwrap()), "-7.00");
+    ///
+    /// assert!(matches!(decimal("2^0.5"), Err(CalcError::NotExact { .. })));
+    /// ```
+    pub fn eval_decimal(&self) -> Result<Decimal> {
+        self.eval_rational().map(Decimal::from_rational)
+    }
+}
@@ -0,0 +1,295 @@
+use std::fmt;
+
+use crate::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// Everything that can go wrong between reading an expression and getting a
+/// number out of it. Errors found while reading the input carry the span they
+/// point at, so they can be shown under the offending part with `render`.
+///
+/// ```
+/// use nac::{CalcError, Expression, Side};
+///
+/// let parse = |input| Expression::root(input).unwrap_err();
+/// let eval = |input| Expression::root(input).unwrap().eval().unwrap_err();
+///
+/// assert!(matches!(parse("2 $ 3"), CalcError::UnexpectedChar { ch: '$', .. }));
+/// assert!(matches!(parse("(1 + 2"), CalcError::UnclosedParen { .. }));
+/// assert!(matches!(parse("1 + 2)"), CalcError::UnmatchedParen { .. }));
+/// assert!(matches!(parse("|3"), CalcError::UnclosedBar { .. }));
+/// assert!(matches!(
+///     parse("2 *"),
+///     CalcError::MissingOperand { side: Some(Side::Right), .. }
+/// ));
+/// assert!(matches!(parse("5 4"), CalcError::MissingOperator { .. }));
+/// assert!(matches!(parse("1.2.3"), CalcError::InvalidNumber { .. }));
+///
+/// assert_eq!(eval("1 // 0"), CalcError::DivByZero { expression: "1 // 0".to_string() });
+/// assert_eq!(eval("foo(1)"), CalcError::UnknownFunction { name: "foo".to_string() });
+/// assert_eq!(eval("y + 1"), CalcError::UnboundVariables { names: vec!["y".to_string()] });
+/// assert_eq!(eval("3 = 4"), CalcError::NotAssignable { target: "3".to_string() });
+/// assert!(matches!(eval("sqrt(-4)"), CalcError::NoRealResult { .. }));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub enum CalcError {
+    UnexpectedChar {
+        ch: char,
+        span: Span,
+    },
+    UnmatchedParen {
+        span: Span,
+    },
+    UnclosedParen {
+        span: Span,
+    },
+    UnclosedBar {
+        span: Span,
+    },
+    /// `side` is `None` for prefix and postfix operators, which only have one.
+    MissingOperand {
+        op: String,
+        side: Option<Side>,
+        span: Span,
+    },
+    UnexpectedOperator {
+        op: String,
+        after: String,
+        span: Span,
+    },
+    MissingOperator {
+        lhs: String,
+        rhs: String,
+        span: Span,
+    },
+    InvalidNumber {
+        message: String,
+        span: Span,
+    },
+    Syntax {
+        message: String,
+        span: Span,
+    },
+    Unresolved {
+        span: Option<Span>,
+    },
+    ParseNumber {
+        literal: String,
+    },
+    /// `expression` is the division that failed, as in `1 / (2 - 2)`.
+    DivByZero {
+        expression: String,
+    },
+    UnknownVariable {
+        name: String,
+    },
+    UnboundVariables {
+        names: Vec<String>,
+    },
+    UnknownFunction {
+        name: String,
+    },
+    ArgumentCount {
+        name: String,
+        expected: String,
+        got: usize,
+    },
+    NotAssignable {
+        target: String,
+    },
+    Math {
+        message: String,
+    },
+    /// The answer is only complex, as for `sqrt(-1)`, which
+    /// [`eval_complex`](crate::Expression::eval_complex) can still work out.
+    NoRealResult {
+        message: String,
+    },
+    NonFinite {
+        expression: String,
+        value: f64,
+    },
+    InexactInteger {
+        literal: String,
+    },
+    NotDifferentiable {
+        expression: String,
+        var: String,
+    },
+    EmptyExpression,
+    NotExact {
+        expression: String,
+    },
+    TooDeep {
+        limit: usize,
+    },
+}
+
+/// Another name for [`CalcError`], so matching on `nac::NacError` works too.
+pub type NacError = CalcError;
+
+impl CalcError {
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            CalcError::UnexpectedChar { span, .. }
+            | CalcError::UnmatchedParen { span }
+            | CalcError::UnclosedParen { span }
+            | CalcError::UnclosedBar { span }
+            | CalcError::MissingOperand { span, .. }
+            | CalcError::UnexpectedOperator { span, .. }
+            | CalcError::MissingOperator { span, .. }
+            | CalcError::InvalidNumber { span, .. }
+            | CalcError::Syntax { span, .. } => Some(*span),
+            CalcError::Unresolved { span } => *span,
+            _ => None,
+        }
+    }
+
+    /// Echoes the line of `input` the error points at with `^~~~` under the
+    /// offending part, then the message. Errors without a span are just the
+    /// message.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let render = |input| Expression::root(input).unwrap_err().render(input);
+    ///
+    /// assert_eq!(render("2 $ 3"), "2 $ 3\n  ^\nunexpected character '$' at position 2");
+    /// assert_eq!(
+    ///     render("1 + 1.2.3"),
+    ///     "1 + 1.2.3\n    ^~~~~\nnumber literal 1.2.3 has more than one decimal point at position 4"
+    /// );
+    /// assert_eq!(render("√4 ×"), "√4 ×\n   ^\nmissing right hand side for * at position 5");
+    /// assert_eq!(render("1 +\n(2"), "line 2:\n(2\n^\nunclosed ( at position 4");
+    /// ```
+    pub fn render(&self, input: &str) -> String {
+        match self.span() {
+            Some(span) => render_span(input, span, &self.to_string()),
+            None => self.to_string(),
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            CalcError::UnexpectedChar { ch, .. } => format!("unexpected character '{ch}'"),
+            CalcError::UnmatchedParen { .. } => "unmatched )".to_string(),
+            CalcError::UnclosedParen { .. } => "unclosed (".to_string(),
+            CalcError::UnclosedBar { .. } => "unclosed |".to_string(),
+            CalcError::MissingOperand { op, side: None, .. } if op == "!" => {
+                "missing operand for ! (factorial goes after its operand, as in 5!)".to_string()
+            }
+            CalcError::MissingOperand { op, side, .. } => match side {
+                Some(Side::Left) => format!("missing left hand side for {op}"),
+                Some(Side::Right) => format!("missing right hand side for {op}"),
+                None => format!("missing operand for {op}"),
+            },
+            CalcError::UnexpectedOperator { op, after, .. } => {
+                format!("unexpected {op} after {after}")
+            }
+            CalcError::MissingOperator { lhs, rhs, .. } => {
+                format!("missing operator between '{lhs}' and '{rhs}'")
+            }
+            CalcError::InvalidNumber { message, .. }
+            | CalcError::Syntax { message, .. }
+            | CalcError::Math { message }
+            | CalcError::NoRealResult { message } => message.clone(),
+            CalcError::Unresolved { .. } => "unresolved expression".to_string(),
+            CalcError::ParseNumber { literal } => format!("invalid number {literal}"),
+            CalcError::DivByZero { expression } => {
+                format!("division by zero in '{expression}'")
+            }
+            CalcError::UnknownVariable { name } => format!("unknown variable: {name}"),
+            CalcError::UnboundVariables { names } if names.len() == 1 => {
+                format!("unknown variable: {}", names[0])
+            }
+            CalcError::UnboundVariables { names } => {
+                format!("unknown variables: {}", names.join(", "))
+            }
+            CalcError::UnknownFunction { name } => format!("unknown function: {name}"),
+            CalcError::ArgumentCount {
+                name,
+                expected,
+                got,
+            } => format!("{name} expects {expected}, got {got}"),
+            CalcError::NotAssignable { target } => {
+                format!("can only assign to a variable, not {target}")
+            }
+            CalcError::NonFinite { expression, value } => {
+                let value = match value {
+                    v if v.is_nan() => "NaN",
+                    v if *v < 0.0 => "-infinity",
+                    _ => "infinity",
+                };
+                format!("{expression} gives {value}")
+            }
+            CalcError::InexactInteger { literal } => format!(
+                "{literal} is past 2^53 and would be rounded to {}",
+                literal.parse::<f64>().unwrap_or(f64::NAN)
+            ),
+            CalcError::NotDifferentiable { expression, var } => {
+                format!("cannot differentiate {expression} with respect to {var}")
+            }
+            CalcError::EmptyExpression => "empty expression".to_string(),
+            CalcError::NotExact { expression } => {
+                format!("{expression} has no exact value as a fraction")
+            }
+            CalcError::TooDeep { limit } => {
+                format!("expression nests more than {limit} levels deep")
+            }
+        }
+    }
+}
+
+impl fmt::Display for CalcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span() {
+            Some(span) => write!(f, "{} at position {}", self.message(), span.start),
+            None => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+impl std::error::Error for CalcError {}
+
+/// Shows `msg` under the line of `input` holding byte offset `pos`, with a `^`
+/// pointing at it. When `input` spans several lines the line number is given
+/// first, since the line shown is then only part of what was typed.
+pub fn render_error(input: &str, pos: usize, msg: &str) -> String {
+    render_span(input, Span::new(pos, pos), msg)
+}
+
+fn render_span(input: &str, span: Span, msg: &str) -> String {
+    let input = input.trim_end_matches(['\r', '\n']);
+
+    let mut start = span.start.min(input.len());
+    while !input.is_char_boundary(start) {
+        start -= 1;
+    }
+
+    let line_start = input[..start].rfind('\n').map_or(0, |at| at + 1);
+    let line_end = input[start..]
+        .find('\n')
+        .map_or(input.len(), |at| start + at);
+    let line = input[line_start..line_end].trim_end_matches('\r');
+
+    let start = start.min(line_start + line.len());
+    let mut end = span.end.clamp(start, line_start + line.len());
+    while !input.is_char_boundary(end) {
+        end += 1;
+    }
+
+    let column = input[line_start..start].chars().count();
+    let width = input[start..end].chars().count().max(1);
+    let carets = format!("{}^{}", " ".repeat(column), "~".repeat(width - 1));
+
+    if line.len() == input.len() {
+        return format!("{line}\n{carets}\n{msg}");
+    }
+
+    let number = input[..line_start].matches('\n').count() + 1;
+    format!("line {number}:\n{line}\n{carets}\n{msg}")
+}
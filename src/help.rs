@@ -0,0 +1,101 @@
+// Symbols, what they do and an example, for `:help <op>`.
+const OPERATORS: &[(&[&str], &str, &str)] = &[
+    (&["!"], "factorial, also of fractions", "5! = 120"),
+    (&["²", "³"], "superscript power", "5² = 25"),
+    (&["^"], "power, grouping from the right", "2^3^2 = 512"),
+    (&["~"], "root, the degree comes first", "3~27 = 3"),
+    (&["√"], "square root", "√16 = 4"),
+    (
+        &["-", "−"],
+        "subtract, or negate a single operand",
+        "-2^2 = -4",
+    ),
+    (
+        &["+"],
+        "add, or keep the sign of a single operand",
+        "2 + 3 = 5",
+    ),
+    (
+        &["*", "×"],
+        "multiply, also implied between values",
+        "2(3 + 4) = 14",
+    ),
+    (&["/", "÷"], "divide", "7 / 2 = 3.5"),
+    (&["//"], "divide and round down", "7 // 2 = 3"),
+    (
+        &["%"],
+        "modulo between values, percent after one",
+        "7 % 3 = 1, 200 + 10% = 220",
+    ),
+    (&["<<"], "shift left", "1 << 4 = 16"),
+    (&[">>"], "shift right", "256 >> 4 = 16"),
+    (&["&"], "bitwise and", "12 & 10 = 8"),
+    (&["^^", "xor"], "bitwise xor", "12 ^^ 10 = 6"),
+    (
+        &["|"],
+        "bitwise or, or absolute value around a value",
+        "12 | 10 = 14, |-3| = 3",
+    ),
+    (
+        &["<", "<=", ">", ">=", "==", "!="],
+        "comparison, 1 if true and 0 if not",
+        "2 < 3 = 1",
+    ),
+    (&["not"], "logical not", "not 0 = 1"),
+    (&["and"], "logical and", "1 and 0 = 0"),
+    (&["or"], "logical or", "1 or 0 = 1"),
+    (&["="], "assign to a variable", "x = 3"),
+    (
+        &[";"],
+        "separate statements, the last one is the result",
+        "x = 3; x^2 = 9",
+    ),
+    (
+        &["#", "0x", "0b", "0o"],
+        "hex, binary and octal literals",
+        "#FF = 0xff = 255",
+    ),
+];
+
+const OVERVIEW: &str = "\
+operators, tightest first:
+    !  ²            factorial, superscript power
+    ^  ~  √         power, root, square root
+    -  +            sign
+    *  /  //  %     multiply, divide, divide rounding down, modulo
+    +  -            add, subtract
+    <<  >>          shift
+    &               bitwise and
+    ^^  xor         bitwise xor
+    |               bitwise or
+    <  <=  >  >=  ==  !=
+    not
+    and
+    or
+    =               assign
+also |x| for absolute value, 50% for percent, ; between statements
+and #FF, 0xFF, 0b101, 0o17 for hex, binary and octal
+
+functions: sqrt sin cos tan asin acos atan ln log10 log2 log(base, x)
+           floor ceil round trunc min max gcd lcm
+           deriv(f, x) for the slope of f at the current x
+constants: pi e tau phi inf, i for complex answers, and ans for the previous result
+
+commands: :deg :rad :hex :dec :frac :money <places> :help :help <op>,
+          and q, quit or exit to leave";
+
+pub fn help(topic: &str) -> String {
+    if topic.is_empty() {
+        return OVERVIEW.to_string();
+    }
+
+    match OPERATORS
+        .iter()
+        .find(|(symbols, _, _)| symbols.contains(&topic))
+    {
+        Some((symbols, description, example)) => {
+            format!("{}  {description}, as in {example}", symbols.join(" "))
+        }
+        None => format!("no help for {topic}, :help lists the operators"),
+    }
+}
@@ -0,0 +1,684 @@
+use std::iter::Peekable;
+
+use crate::{CalcError, Result, Span};
+
+/// One piece of the input, with the bytes it came from.
+///
+/// ```
+/// use nac::{lex, Op, Span, TokenKind};
+///
+/// let tokens = lex("2 + x").unwrap();
+///
+/// assert_eq!(tokens[1].kind, TokenKind::Operator(Op::Plus));
+/// assert_eq!(tokens[1].span, Span { start: 2, end: 3 });
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    /// A decimal literal as `f64` reads it, exponent included.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let eval = |input| Expression::root(input).unwrap().eval().unwrap();
+    ///
+    /// assert_eq!(eval("6.02e23"), 6.02e23);
+    /// assert_eq!(eval("1_000.5"), 1000.5);
+    /// ```
+    Number(String),
+    /// `#FF` or `0xFF`.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let eval = |input| Expression::root(input).unwrap().eval().unwrap();
+    ///
+    /// assert_eq!(eval("#ff + 0x10"), 271.0);
+    /// ```
+    HexNumber(u64),
+    /// `0b1010`, with `_` allowed between digits.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let eval = |input| Expression::root(input).unwrap().eval().unwrap();
+    ///
+    /// assert_eq!(eval("0b1111_0000"), 240.0);
+    /// ```
+    BinaryNumber(u64),
+    /// `0o755`, with `_` allowed between digits.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let eval = |input| Expression::root(input).unwrap().eval().unwrap();
+    ///
+    /// assert_eq!(eval("0o755"), 493.0);
+    /// ```
+    OctalNumber(u64),
+    Ident(String),
+    Operator(Op),
+    /// A run of superscript digits, a power of whatever comes before it.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let eval = |input| Expression::root(input).unwrap().eval().unwrap();
+    ///
+    /// assert_eq!(eval("3² + 4²"), 25.0);
+    /// ```
+    Superscript(String),
+    OpenParen,
+    CloseParen,
+    Bar,
+    Comma,
+    Semicolon,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    DoubleSlash,
+    Percent,
+    Caret,
+    Tilde,
+    Root,
+    Bang,
+    Shl,
+    Shr,
+    Amp,
+    Xor,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    EqEq,
+    Ne,
+    Assign,
+    And,
+    Or,
+    Not,
+}
+
+/// Splits `input` into tokens without building or evaluating anything.
+///
+/// ```
+/// use nac::{lex, Op, TokenKind};
+///
+/// let kinds: Vec<TokenKind> = lex("2 * (0xff - x)")
+///     .unwrap()
+///     .into_iter()
+///     .map(|token| token.kind)
+///     .collect();
+///
+/// assert_eq!(
+///     kinds,
+///     [
+///         TokenKind::Number("2".to_string()),
+///         TokenKind::Operator(Op::Star),
+///         TokenKind::OpenParen,
+///         TokenKind::HexNumber(255),
+///         TokenKind::Operator(Op::Minus),
+///         TokenKind::Ident("x".to_string()),
+///         TokenKind::CloseParen,
+///     ]
+/// );
+/// ```
+pub fn lex(input: &str) -> Result<Vec<Token>> {
+    let (tokens, errors) = lex_recovering(input);
+    match errors.into_iter().next() {
+        Some(e) => Err(e),
+        None => Ok(tokens),
+    }
+}
+
+/// Lexes all of `input`, collecting every error instead of stopping at the
+/// first. A malformed number still becomes a token so the parts around it
+/// line up, and a run of unexpected characters is reported once.
+pub(crate) fn lex_recovering(input: &str) -> (Vec<Token>, Vec<CalcError>) {
+    let mut tokens = vec![];
+    let mut errors = vec![];
+    let mut bad_chars_end = None;
+    let mut chars = input.char_indices().peekable();
+
+    while let Some((pos, char)) = chars.next() {
+        if char.is_whitespace() {
+            continue;
+        }
+
+        let kind = next_kind(&mut chars, pos, char);
+        let end = chars.peek().map_or(input.len(), |&(at, _)| at);
+        let span = Span::new(pos, end);
+
+        match kind {
+            Ok(kind) => tokens.push(Token { kind, span }),
+            Err(CalcError::UnexpectedChar { .. }) if bad_chars_end == Some(pos) => {
+                if let Some(CalcError::UnexpectedChar { span, .. }) = errors.last_mut() {
+                    span.end = end;
+                }
+                bad_chars_end = Some(end);
+            }
+            Err(e) => {
+                match e {
+                    CalcError::InvalidNumber { .. } => tokens.push(Token {
+                        kind: TokenKind::Number("0".to_string()),
+                        span,
+                    }),
+                    CalcError::UnexpectedChar { .. } => bad_chars_end = Some(end),
+                    _ => {}
+                }
+                errors.push(e);
+            }
+        }
+    }
+
+    (tokens, errors)
+}
+
+fn next_kind(
+    chars: &mut Peekable<impl Iterator<Item = (usize, char)>>,
+    pos: usize,
+    char: char,
+) -> Result<TokenKind> {
+    let kind = if char == '0' && matches!(chars.peek(), Some((_, 'x' | 'X'))) {
+        chars.next();
+        TokenKind::HexNumber(parse_radix_literal(chars, 16, "0x", pos)?)
+    } else if char == '0' && matches!(chars.peek(), Some((_, 'b' | 'B'))) {
+        chars.next();
+        TokenKind::BinaryNumber(parse_radix_literal(chars, 2, "0b", pos)?)
+    } else if char == '0' && matches!(chars.peek(), Some((_, 'o' | 'O'))) {
+        chars.next();
+        TokenKind::OctalNumber(parse_radix_literal(chars, 8, "0o", pos)?)
+    } else if char.is_ascii_digit() || char == '.' && matches!(chars.peek(), Some((_, '0'..='9'))) {
+        TokenKind::Number(parse_decimal_literal(chars, char, pos)?)
+    } else if char == '_' && matches!(chars.peek(), Some((_, '0'..='9'))) {
+        return Err(CalcError::InvalidNumber {
+            message: "number literal cannot start with an underscore".to_string(),
+            span: Span::new(pos, pos + 1),
+        });
+    } else if char.is_alphabetic() {
+        let mut name = String::new();
+        name.push(char);
+
+        while let Some(&(_, nxt)) = chars.peek() {
+            if nxt.is_alphabetic() || nxt.is_ascii_digit() || nxt == '_' {
+                name.push(nxt);
+                chars.next();
+            } else {
+                break;
+            }
+        }
+
+        match name.as_str() {
+            "xor" => TokenKind::Operator(Op::Xor),
+            "and" => TokenKind::Operator(Op::And),
+            "or" => TokenKind::Operator(Op::Or),
+            "not" => TokenKind::Operator(Op::Not),
+            _ => TokenKind::Ident(name),
+        }
+    } else if char == '#' {
+        TokenKind::HexNumber(parse_radix_literal(chars, 16, "#", pos)?)
+    } else if let Some(digit) = superscript_digit(char) {
+        let mut exponent = String::from(digit);
+        while let Some(digit) = chars.peek().and_then(|&(_, c)| superscript_digit(c)) {
+            exponent.push(digit);
+            chars.next();
+        }
+
+        TokenKind::Superscript(exponent)
+    } else {
+        match char {
+            '+' => TokenKind::Operator(Op::Plus),
+            '-' | '−' => TokenKind::Operator(Op::Minus),
+            '*' | '×' => TokenKind::Operator(Op::Star),
+            '/' if eat(chars, '/') => TokenKind::Operator(Op::DoubleSlash),
+            '/' | '÷' => TokenKind::Operator(Op::Slash),
+            '%' => TokenKind::Operator(Op::Percent),
+            '^' if eat(chars, '^') => TokenKind::Operator(Op::Xor),
+            '^' => TokenKind::Operator(Op::Caret),
+            '~' => TokenKind::Operator(Op::Tilde),
+            '√' => TokenKind::Operator(Op::Root),
+            '<' if eat(chars, '<') => TokenKind::Operator(Op::Shl),
+            '>' if eat(chars, '>') => TokenKind::Operator(Op::Shr),
+            '<' if eat(chars, '=') => TokenKind::Operator(Op::Le),
+            '<' => TokenKind::Operator(Op::Lt),
+            '>' if eat(chars, '=') => TokenKind::Operator(Op::Ge),
+            '>' => TokenKind::Operator(Op::Gt),
+            '=' if eat(chars, '=') => TokenKind::Operator(Op::EqEq),
+            '=' => TokenKind::Operator(Op::Assign),
+            '!' if eat(chars, '=') => TokenKind::Operator(Op::Ne),
+            '!' => TokenKind::Operator(Op::Bang),
+            '&' if eat(chars, '&') => {
+                return Err(CalcError::Syntax {
+                    message: "unexpected && (bitwise and is &, xor is ^^ or xor, or is |)"
+                        .to_string(),
+                    span: Span::new(pos, pos + 2),
+                });
+            }
+            '&' => TokenKind::Operator(Op::Amp),
+            '|' => TokenKind::Bar,
+            '(' => TokenKind::OpenParen,
+            ')' => TokenKind::CloseParen,
+            ',' => TokenKind::Comma,
+            ';' => TokenKind::Semicolon,
+            _ => {
+                return Err(CalcError::UnexpectedChar {
+                    ch: char,
+                    span: Span::new(pos, pos + char.len_utf8()),
+                });
+            }
+        }
+    };
+
+    Ok(kind)
+}
+
+fn eat(chars: &mut Peekable<impl Iterator<Item = (usize, char)>>, expected: char) -> bool {
+    chars.next_if(|&(_, c)| c == expected).is_some()
+}
+
+fn parse_decimal_literal(
+    chars: &mut Peekable<impl Iterator<Item = (usize, char)>>,
+    first: char,
+    pos: usize,
+) -> Result<String> {
+    let mut raw = String::new();
+    raw.push(first);
+
+    while let Some(&(_, nxt)) = chars.peek() {
+        if nxt.is_ascii_digit() || nxt == '.' || nxt == '_' {
+            raw.push(nxt);
+            chars.next();
+        } else {
+            break;
+        }
+    }
+
+    if raw.matches('.').count() > 1 {
+        return Err(CalcError::InvalidNumber {
+            message: format!("number literal {raw} has more than one decimal point"),
+            span: Span::new(pos, pos + raw.len()),
+        });
+    }
+
+    check_underscores(&raw, pos, &format!("number literal {raw}"))?;
+
+    let mut buf = raw.replace('_', "");
+
+    if buf.starts_with('.') {
+        buf.insert(0, '0');
+    }
+
+    if buf.ends_with('.') {
+        buf.push('0');
+    }
+
+    // An `e` glued to a number always starts an exponent, so `2e` is an error
+    // rather than `2 * e`; the constant `e` has to stand on its own.
+    if let Some(&(_, e)) = chars.peek().filter(|(_, c)| *c == 'e' || *c == 'E') {
+        let mantissa_len = buf.len();
+        buf.push(e);
+        chars.next();
+
+        if let Some(&(_, sign)) = chars.peek().filter(|(_, c)| *c == '+' || *c == '-') {
+            buf.push(sign);
+            chars.next();
+        }
+
+        let mut has_digits = false;
+        while let Some(&(_, nxt)) = chars.peek() {
+            if nxt.is_ascii_digit() {
+                has_digits = true;
+                buf.push(nxt);
+                chars.next();
+            } else {
+                break;
+            }
+        }
+
+        if !has_digits {
+            return Err(CalcError::InvalidNumber {
+                message: format!(
+                    "missing exponent digits after {e} in {buf} (write {}*e to multiply by Euler's number)",
+                    buf.trim_end_matches(['e', 'E', '+', '-'])
+                ),
+                span: Span::new(pos, pos + raw.len() + buf.len() - mantissa_len),
+            });
+        }
+    }
+
+    Ok(buf)
+}
+
+fn parse_radix_literal(
+    chars: &mut Peekable<impl Iterator<Item = (usize, char)>>,
+    radix: u32,
+    prefix: &str,
+    pos: usize,
+) -> Result<u64> {
+    let name = match radix {
+        2 => "binary",
+        8 => "octal",
+        _ => "hex",
+    };
+
+    let mut raw = String::new();
+    let mut end = pos + prefix.len();
+
+    while let Some(&(at, nxt)) = chars.peek() {
+        if !nxt.is_digit(radix) && nxt != '_' {
+            break;
+        }
+        raw.push(nxt);
+        end = at + 1;
+        chars.next();
+    }
+
+    if let Some(&(at, invalid)) = chars.peek().filter(|(_, c)| c.is_ascii_alphanumeric()) {
+        return Err(CalcError::InvalidNumber {
+            message: format!("invalid digit '{invalid}' in {name} literal"),
+            span: Span::new(at, at + 1),
+        });
+    }
+
+    check_underscores(
+        &raw,
+        pos + prefix.len(),
+        &format!("{name} literal {prefix}{raw}"),
+    )?;
+
+    let buf = raw.replace('_', "");
+
+    if buf.is_empty() {
+        return Err(CalcError::InvalidNumber {
+            message: format!(
+                "{name} literal '{prefix}' must be followed by at least one {name} digit"
+            ),
+            span: Span::new(pos, end),
+        });
+    }
+
+    let max = match radix {
+        2 => "64 digits",
+        8 => "0o1777777777777777777777",
+        _ => "16 digits",
+    };
+
+    u64::from_str_radix(&buf, radix).map_err(|_| CalcError::InvalidNumber {
+        message: format!("{name} literal too large: {prefix}{buf} (max {max})"),
+        span: Span::new(pos, end),
+    })
+}
+
+fn superscript_digit(c: char) -> Option<char> {
+    match c {
+        '⁰' => Some('0'),
+        '¹' => Some('1'),
+        '²' => Some('2'),
+        '³' => Some('3'),
+        '⁴' => Some('4'),
+        '⁵' => Some('5'),
+        '⁶' => Some('6'),
+        '⁷' => Some('7'),
+        '⁸' => Some('8'),
+        '⁹' => Some('9'),
+        _ => None,
+    }
+}
+
+fn check_underscores(digits: &str, start: usize, literal: &str) -> Result<()> {
+    let bytes = digits.as_bytes();
+
+    for (i, _) in digits.match_indices('_') {
+        let problem = match (i.checked_sub(1).map(|j| bytes[j]), bytes.get(i + 1)) {
+            (None, _) => "leading underscore",
+            (_, None) => "trailing underscore",
+            (Some(b'_'), _) | (_, Some(b'_')) => "doubled underscore",
+            (Some(b'.'), _) | (_, Some(b'.')) => "underscore next to the decimal point",
+            _ => continue,
+        };
+
+        return Err(CalcError::InvalidNumber {
+            message: format!("{problem} in {literal}"),
+            span: Span::new(start + i, start + i + 1),
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Expression;
+
+    fn try_eval(input: &str) -> Result<f64> {
+        Expression::root(input)?.eval()
+    }
+
+    fn eval(input: &str) -> f64 {
+        try_eval(input).unwrap_or_else(|err| panic!("{input}: {err}"))
+    }
+
+    fn err(input: &str) -> String {
+        match try_eval(input) {
+            Ok(value) => panic!("{input} gave {value}"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn scientific_notation() {
+        for (input, value) in [
+            ("6.02e23", 6.02e23),
+            ("2.5e-3", 0.0025),
+            ("1e+5", 1e5),
+            ("1E-5", 1e-5),
+        ] {
+            assert_eq!(eval(input), value, "{input}");
+        }
+        assert_eq!(err("1e"), "missing exponent digits after e in 1e (write 1*e to multiply by Euler's number) at position 0");
+        assert_eq!(
+            lex("e5").unwrap()[0].kind,
+            TokenKind::Ident("e5".to_string())
+        );
+    }
+
+    #[test]
+    fn binary_literals() {
+        for (input, value) in [
+            ("0b1010", 10.0),
+            ("0b1111_0000", 240.0),
+            ("0b101 * 2 + 1", 11.0),
+        ] {
+            assert_eq!(eval(input), value, "{input}");
+        }
+        assert_eq!(
+            err("0b"),
+            "binary literal '0b' must be followed by at least one binary digit at position 0"
+        );
+        assert_eq!(
+            err("0b2"),
+            "invalid digit '2' in binary literal at position 2"
+        );
+        let digits = "0".repeat(63);
+        assert_eq!(eval(&format!("0b1{digits}")), 2f64.powi(63));
+        assert!(err(&format!("0b1{digits}0")).starts_with("binary literal too large"));
+    }
+
+    #[test]
+    fn octal_literals() {
+        for (input, value) in [
+            ("0o755", 493.0),
+            ("0o10 + 0o10", 16.0),
+            ("0o17 * 2 - 0b1", 29.0),
+        ] {
+            assert_eq!(eval(input), value, "{input}");
+        }
+        assert_eq!(
+            err("0o9"),
+            "invalid digit '9' in octal literal at position 2"
+        );
+        assert_eq!(
+            err("0o"),
+            "octal literal '0o' must be followed by at least one octal digit at position 0"
+        );
+    }
+
+    // `_` can sit between digits, and is an error anywhere else.
+    #[test]
+    fn digit_separators() {
+        for (input, value) in [("1_000 + 1", 1001.0), ("1.234_567", 1.234567)] {
+            assert_eq!(eval(input), value, "{input}");
+        }
+        assert_eq!(
+            err("_5"),
+            "number literal cannot start with an underscore at position 0"
+        );
+        assert_eq!(
+            err("5_"),
+            "trailing underscore in number literal 5_ at position 1"
+        );
+        assert_eq!(
+            err("5__0"),
+            "doubled underscore in number literal 5__0 at position 1"
+        );
+        assert_eq!(
+            err("1_.5"),
+            "underscore next to the decimal point in number literal 1_.5 at position 1"
+        );
+        assert_eq!(
+            err("1._5"),
+            "underscore next to the decimal point in number literal 1._5 at position 2"
+        );
+    }
+
+    #[test]
+    fn several_decimal_points() {
+        assert_eq!(
+            err("1.2.3"),
+            "number literal 1.2.3 has more than one decimal point at position 0"
+        );
+        assert_eq!(
+            err("1..2"),
+            "number literal 1..2 has more than one decimal point at position 0"
+        );
+        assert_eq!(
+            err(".5."),
+            "number literal .5. has more than one decimal point at position 0"
+        );
+        assert_eq!(
+            err("2 + 1.2.3"),
+            "number literal 1.2.3 has more than one decimal point at position 4"
+        );
+    }
+
+    #[test]
+    fn leading_dot() {
+        for (input, value) in [
+            (".5+.5", 1.0),
+            ("(.25)^.5", 0.5),
+            (".5 * 8", 4.0),
+            ("5.", 5.0),
+        ] {
+            assert_eq!(eval(input), value, "{input}");
+        }
+    }
+
+    #[test]
+    fn empty_hex_literal() {
+        assert_eq!(eval("#ff + 0x10"), 271.0);
+        assert_eq!(
+            err("#"),
+            "hex literal '#' must be followed by at least one hex digit at position 0"
+        );
+        assert_eq!(
+            err("# 5"),
+            "hex literal '#' must be followed by at least one hex digit at position 0"
+        );
+        assert_eq!(
+            err("# + 2"),
+            "hex literal '#' must be followed by at least one hex digit at position 0"
+        );
+        assert_eq!(err("#g"), "invalid digit 'g' in hex literal at position 1");
+    }
+
+    #[test]
+    fn superscript_powers() {
+        for (input, value) in [("3² + 4²", 25.0), ("2¹⁰", 1024.0), ("(1 + 1)³", 8.0)] {
+            assert_eq!(eval(input), value, "{input}");
+        }
+        assert_eq!(
+            err("²"),
+            "superscript exponent has nothing to raise at position 0"
+        );
+    }
+
+    // An `e` straight after digits starts the exponent, and then has to be
+    // followed by one. Anywhere else it is Euler's number.
+    #[test]
+    fn e_after_digits() {
+        for (input, value) in [
+            ("e", std::f64::consts::E),
+            ("1e3", 1000.0),
+            ("2 e", 2.0 * std::f64::consts::E),
+        ] {
+            assert_eq!(eval(input), value, "{input}");
+        }
+        assert_eq!(err("2e"), "missing exponent digits after e in 2e (write 2*e to multiply by Euler's number) at position 0");
+    }
+
+    #[test]
+    fn tokens() {
+        let tokens: Vec<(TokenKind, usize, usize)> = lex("0b101 + √x! >= #F; 2²")
+            .unwrap()
+            .into_iter()
+            .map(|token| (token.kind, token.span.start, token.span.end))
+            .collect();
+
+        assert_eq!(
+            tokens,
+            [
+                (TokenKind::BinaryNumber(5), 0, 5),
+                (TokenKind::Operator(Op::Plus), 6, 7),
+                (TokenKind::Operator(Op::Root), 8, 11),
+                (TokenKind::Ident("x".to_string()), 11, 12),
+                (TokenKind::Operator(Op::Bang), 12, 13),
+                (TokenKind::Operator(Op::Ge), 14, 16),
+                (TokenKind::HexNumber(15), 17, 19),
+                (TokenKind::Semicolon, 19, 20),
+                (TokenKind::Number("2".to_string()), 21, 22),
+                (TokenKind::Superscript("2".to_string()), 22, 24),
+            ]
+        );
+    }
+
+    // What these gave before `root` was built on tokens.
+    #[test]
+    fn results_unchanged_by_the_lexer() {
+        for (input, value) in [
+            ("1 + 2 * 3", 7.0),
+            ("(2 ^ 3) ^ 2", 64.0),
+            ("-7 // 2", -4.0),
+            ("3 ~ 27 + √16", 7.0),
+            ("5! - 3! ^ 2", 84.0),
+            ("|3 - 5| * 2", 4.0),
+            ("200 + 10%", 220.0),
+            ("#ff & #0f | 1 << 4", 31.0),
+            ("1 + 2 < 4 and 2 * 3 == 6", 1.0),
+            ("(1 + 1)(2 + 2)", 8.0),
+            ("x = 2; y = x + 1; x * y", 6.0),
+            ("max(1, 2, 3) + min(4, 5)", 7.0),
+        ] {
+            assert_eq!(eval(input), value, "{input}");
+        }
+    }
+}
@@ -1,297 +1,3656 @@
-use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::FromStr;
 
-#[derive(Debug, Default)]
+#[cfg(feature = "capi")]
+mod capi;
+mod complex;
+#[cfg(feature = "decimal")]
+mod decimal;
+mod error;
+mod lexer;
+mod rational;
+#[cfg(feature = "serde")]
+mod serialize;
 
+#[cfg(feature = "capi")]
+pub use capi::{
+    nac_eval, NAC_EVAL_ERROR, NAC_INVALID_UTF8, NAC_NULL_POINTER, NAC_OK, NAC_PARSE_ERROR,
+};
+pub use complex::Complex;
+#[cfg(feature = "decimal")]
+pub use decimal::Decimal;
+pub use error::{render_error, CalcError, NacError, Side};
+pub use lexer::{lex, Op, Token, TokenKind};
+pub use rational::Rational;
+
+type Result<T, E = CalcError> = std::result::Result<T, E>;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct OpParams {
     lhs: Option<Box<Expression>>,
     rhs: Option<Box<Expression>>,
 }
 
-#[derive(Debug)]
+/// Byte offsets into the input, `start` included and `end` not, for the part
+/// an error is about.
+///
+/// ```
+/// use nac::{Expression, Span};
+///
+/// let input = "5 +";
+/// let err = Expression::root(input).unwrap_err();
+///
+/// assert_eq!(err.span(), Some(Span { start: 2, end: 3 }));
+/// assert_eq!(err.render(input), "5 +\n  ^\nmissing right hand side for + at position 2");
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl OpParams {
+    fn new(lhs: Expression, rhs: Expression) -> OpParams {
+        OpParams {
+            lhs: Some(Box::new(lhs)),
+            rhs: Some(Box::new(rhs)),
+        }
+    }
+}
+
+// The derived drop would recurse once per term down the left of `1 + 2 + 3`
+// and so on, so operands are taken out and dropped from a list instead.
+impl Drop for OpParams {
+    fn drop(&mut self) {
+        let mut operands: Vec<Box<Expression>> =
+            self.lhs.take().into_iter().chain(self.rhs.take()).collect();
+
+        while let Some(mut exp) = operands.pop() {
+            if let Some(params) = exp.params_mut() {
+                operands.extend(params.lhs.take());
+                operands.extend(params.rhs.take());
+            }
+        }
+    }
+}
+
+impl Span {
+    fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+}
+
+/// How deeply [`Expression::root`] lets an input nest, as counted by
+/// [`Expression::root_with_max_depth`]. It leaves plenty of room on a default
+/// stack for evaluating, printing and dropping the tree, other than printing a
+/// run of many thousands of left-associative terms.
+pub const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AngleMode {
+    #[default]
+    Radians,
+    Degrees,
+}
+
+impl AngleMode {
+    fn mode_to_radians(self, angle: f64) -> f64 {
+        match self {
+            AngleMode::Radians => angle,
+            AngleMode::Degrees => angle.to_radians(),
+        }
+    }
+
+    fn radians_to_mode(self, angle: f64) -> f64 {
+        match self {
+            AngleMode::Radians => angle,
+            AngleMode::Degrees => angle.to_degrees(),
+        }
+    }
+}
+
+/// Knobs for [`Expression::eval_with_options`]. The defaults match `eval`.
+///
+/// ```
+/// use nac::{CalcError, EvalOptions, Expression, ZeroPowZero};
+///
+/// for (input, shown) in [("1/0", "1 / 0"), ("0/0", "0 / 0"), ("5%0", "5 % 0"), ("1/(2-2)", "1 / (2 - 2)")] {
+///     let exp = Expression::root(input).unwrap();
+///     let expression = shown.to_string();
+///     assert_eq!(exp.eval(), Err(CalcError::DivByZero { expression }));
+/// }
+/// let err = Expression::root("1 + 1/(2-2)").unwrap().eval().unwrap_err();
+/// assert_eq!(err.to_string(), "division by zero in '1 / (2 - 2)'");
+///
+/// let ieee = EvalOptions {
+///     allow_non_finite: true,
+///     ..EvalOptions::default()
+/// };
+/// let eval = |input| Expression::root(input).unwrap().eval_with_options(ieee).unwrap();
+/// assert_eq!(eval("1/0"), f64::INFINITY);
+/// assert!(eval("0/0").is_nan());
+/// assert!(eval("5 % 0").is_nan());
+/// assert_eq!(eval("1/(2-2)"), f64::INFINITY);
+///
+/// let checked = EvalOptions {
+///     check_finite: true,
+///     ..EvalOptions::default()
+/// };
+/// let err = Expression::root("1 + asin(2)").unwrap().eval_with_options(checked);
+/// assert_eq!(err.unwrap_err().to_string(), "asin(2) gives NaN");
+///
+/// let err = Expression::root("2 * 10^400").unwrap().eval_with_options(checked);
+/// assert_eq!(err.unwrap_err().to_string(), "10 ^ 400 gives infinity");
+///
+/// let exact = EvalOptions {
+///     exact_integers: true,
+///     ..EvalOptions::default()
+/// };
+/// let big = Expression::root("0x20000000000001 - 1").unwrap();
+/// assert_eq!(big.eval(), Ok(9007199254740991.0));
+/// assert_eq!(big.inexact_integers(), ["9007199254740993"]);
+/// assert!(big.eval_with_options(exact).is_err());
+/// assert!(Expression::root("2^60 + 0x1000000000000000").unwrap().eval_with_options(exact).is_ok());
+///
+/// let zero = |input, options| Expression::root(input).unwrap().eval_with_options(options);
+///
+/// let nan = EvalOptions {
+///     zero_pow_zero: ZeroPowZero::Nan,
+///     ..EvalOptions::default()
+/// };
+/// let error = EvalOptions {
+///     zero_pow_zero: ZeroPowZero::Error,
+///     ..EvalOptions::default()
+/// };
+/// assert_eq!(zero("0^0", EvalOptions::default()), Ok(1.0));
+/// assert!(zero("0^0", nan).unwrap().is_nan());
+/// assert_eq!(zero("0^0", error).unwrap_err().to_string(), "0 ^ 0 is undefined");
+/// for options in [nan, error] {
+///     assert_eq!(zero("0^2 + 2^0", options), Ok(1.0));
+/// }
+///
+/// let no_zero_root = EvalOptions {
+///     zero_root_error: true,
+///     ..EvalOptions::default()
+/// };
+/// assert_eq!(zero("0 ~ 5", EvalOptions::default()), Ok(f64::INFINITY));
+/// assert_eq!(zero("0 ~ 0.5", EvalOptions::default()), Ok(0.0));
+/// for input in ["0 ~ 5", "0 ~ 0.5", "0 ~ 1"] {
+///     assert!(zero(input, no_zero_root).is_err());
+/// }
+/// assert_eq!(zero("2 ~ 9", no_zero_root), Ok(3.0));
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EvalOptions {
+    /// Let `/` and `%` by zero give infinity or NaN like IEEE floats do,
+    /// rather than failing with [`CalcError::DivByZero`]. `//` by zero is
+    /// always an error.
+    pub allow_non_finite: bool,
+    /// Fail with [`CalcError::NonFinite`] as soon as any step, a number or
+    /// variable included, gives NaN or infinity.
+    pub check_finite: bool,
+    /// Fail with [`CalcError::InexactInteger`] on whole number literals that
+    /// an `f64` can only hold rounded, which is most of them past 2^53.
+    pub exact_integers: bool,
+    /// What `0 ^ 0` comes to, 1 unless a caller wants otherwise.
+    pub zero_pow_zero: ZeroPowZero,
+    /// Fail on a root of degree 0, as in `0 ~ 5`, instead of giving whatever
+    /// raising to `1 / 0` does.
+    pub zero_root_error: bool,
+}
+
+/// The choices for [`EvalOptions::zero_pow_zero`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ZeroPowZero {
+    #[default]
+    One,
+    Nan,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Radix {
+    #[default]
+    Decimal,
+    Hex,
+}
+
+// Hex output only makes sense for whole numbers, so anything else (fractions,
+// infinities, NaN, values past the i64 range) is printed in decimal instead.
+pub fn format_result(value: f64, radix: Radix) -> String {
+    let whole = value.fract() == 0.0 && value.abs() <= i64::MAX as f64;
+
+    match radix {
+        Radix::Hex if whole && value < 0.0 => format!("-#{:X}", (value as i64).unsigned_abs()),
+        Radix::Hex if whole => format!("#{:X}", value as i64),
+        _ => value.to_string(),
+    }
+}
+
+/// A parsed expression. With the `serde` feature it can be stored and loaded
+/// again, ready to evaluate.
+///
+/// ```
+/// # #[cfg(feature = "serde")]
+/// # {
+/// use nac::Expression;
+///
+/// let exp = Expression::root("2^(3+1)").unwrap();
+/// let json = serde_json::to_string(&exp).unwrap();
+/// let loaded: Expression = serde_json::from_str(&json).unwrap();
+///
+/// assert_eq!(loaded.eval().unwrap(), 16.0);
+/// # }
+/// ```
+///
+/// Operators are tagged with the name of the function that builds them, and a
+/// document with an operand missing is turned down rather than loaded.
+///
+/// ```
+/// # #[cfg(feature = "serde")]
+/// # {
+/// use nac::Expression;
+///
+/// let json = serde_json::to_string(&Expression::add(
+///     Expression::number(1.5),
+///     Expression::var("x"),
+/// ))
+/// .unwrap();
+/// assert_eq!(json, r#"{"op":"add","lhs":{"num":"1.5"},"rhs":{"var":"x"}}"#);
+///
+/// for input in ["1 + 2 * 3", "-(2^3^2) % 7", "x = 4; √x + 3~27", "max(1, |2 - 5|)!", "200 + 10%"] {
+///     let exp = Expression::root(input).unwrap();
+///     let loaded: Expression = serde_json::from_str(&serde_json::to_string(&exp).unwrap()).unwrap();
+///     assert_eq!(loaded, exp);
+///     assert_eq!(loaded.eval(), exp.eval());
+/// }
+///
+/// // Numbers are kept as they were written, so ones a float would round
+/// // come back the same.
+/// for input in ["11e32", "9007199254740993", "0.1000000000000000000001"] {
+///     let exp = Expression::root(input).unwrap();
+///     let loaded: Expression = serde_json::from_str(&serde_json::to_string(&exp).unwrap()).unwrap();
+///     assert_eq!(loaded.to_string(), input);
+/// }
+///
+/// let old_style: Expression = serde_json::from_str(r#"{"num":1.5}"#).unwrap();
+/// assert_eq!(old_style.eval(), Ok(1.5));
+/// assert!(serde_json::from_str::<Expression>(r#"{"num":"1.5.2"}"#).is_err());
+///
+/// let missing_rhs = r#"{"op":"add","lhs":{"num":1}}"#;
+/// assert!(serde_json::from_str::<Expression>(missing_rhs).is_err());
+/// # }
+/// ```
+#[derive(Debug, Clone)]
 pub enum Expression {
     Unit(String),
+    Var(String),
     Add(OpParams),
     Sub(OpParams),
+    /// `*`, also implied between two values other than a pair of numbers.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let eval = |input| Expression::root(input).unwrap().eval().unwrap();
+    ///
+    /// assert_eq!(eval("(1 + 1)(2 + 2)"), 8.0);
+    /// assert_eq!(eval("x = 3; 2x"), 6.0);
+    /// ```
     Mul(OpParams),
     Div(OpParams),
+    /// `//`, division rounded down.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let eval = |input| Expression::root(input).unwrap().eval().unwrap();
+    ///
+    /// assert_eq!(eval("7//2"), 3.0);
+    /// assert_eq!(eval("-7//2"), -4.0);
+    /// ```
+    IntDiv(OpParams),
     Mod(OpParams),
+    /// `^`, grouping from the right like it does on paper, while the
+    /// operators below it group from the left.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let eval = |input| Expression::root(input).unwrap().eval().unwrap();
+    ///
+    /// assert_eq!(eval("2^3^2"), 512.0);
+    /// assert_eq!(eval("16/4/2"), 2.0);
+    /// ```
     Pow(OpParams),
+    /// `n ~ x`, the n-th root of x. With nothing before it, `~` is a square
+    /// root, like `√`.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let eval = |input| Expression::root(input).unwrap().eval().unwrap();
+    ///
+    /// assert_eq!(eval("~9"), 3.0);
+    /// assert_eq!(eval("~~16"), 2.0);
+    /// ```
     Root(OpParams),
+    /// `<<`, and `>>` below. Shifts bind looser than sums and tighter than
+    /// `&`, and the amount has to be from 0 to 63.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let eval = |input| Expression::root(input).unwrap().eval().unwrap();
+    ///
+    /// assert_eq!(eval("1 << 10"), 1024.0);
+    /// assert_eq!(eval("#ff00 >> 8"), 255.0);
+    /// ```
+    Shl(OpParams),
+    Shr(OpParams),
+    /// `&`, and with `^^` (or `xor`) and `|` below it, on whole numbers. `^`
+    /// stays a power.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let eval = |input| Expression::root(input).unwrap().eval().unwrap();
+    ///
+    /// assert_eq!(eval("#ff & #0f"), 15.0);
+    /// assert_eq!(eval("5 xor 3"), 6.0);
+    /// ```
+    BitAnd(OpParams),
+    BitXor(OpParams),
+    BitOr(OpParams),
+    Lt(OpParams),
+    Le(OpParams),
+    Gt(OpParams),
+    Ge(OpParams),
+    Eq(OpParams),
+    Ne(OpParams),
+    /// `and`, with `or` below it and `not` as a prefix. 0 is false and
+    /// anything else true, the result is 0 or 1, and the right hand side is
+    /// only evaluated when it matters.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let eval = |input| Expression::root(input).unwrap().eval().unwrap();
+    ///
+    /// assert_eq!(eval("1 < 2 and 3 > 2"), 1.0);
+    /// assert_eq!(eval("not 5"), 0.0);
+    /// ```
+    And(OpParams),
+    Or(OpParams),
+    Not(Option<Box<Expression>>),
+    Assign(OpParams),
+    /// A leading minus. It takes what follows up to the next operator looser
+    /// than a sign, so powers go first and `-2^2` is -4.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let eval = |input| Expression::root(input).unwrap().eval().unwrap();
+    ///
+    /// assert_eq!(eval("-2^2"), -4.0);
+    /// assert_eq!(eval("2^-3"), 0.125);
+    /// ```
+    Neg(Option<Box<Expression>>),
+    Pos(Option<Box<Expression>>),
+    /// `5!`, binding tighter than a power, so `2^3!` is `2^6`. A fraction
+    /// goes through the gamma function.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let eval = |input| Expression::root(input).unwrap().eval().unwrap();
+    ///
+    /// assert_eq!(eval("2^3!"), 64.0);
+    /// assert_eq!(eval("(2+3)!"), 120.0);
+    /// ```
+    Fact(Box<Expression>),
+    /// `|x|`. Bars nest, and one that can't close a value opens a new pair,
+    /// as does one touching the value after a pair, so pairs side by side
+    /// multiply.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let eval = |input| Expression::root(input).unwrap().eval().unwrap();
+    ///
+    /// assert_eq!(eval("2*|1-4|"), 6.0);
+    /// assert_eq!(eval("|2 - |3-5||"), 0.0);
+    /// assert_eq!(eval("|-3| |4|"), 12.0);
+    /// ```
+    Abs(Box<Expression>),
+    /// `50%`, read as a percentage when `%` ends its operand, and as mod when
+    /// something that can start one follows. A sign after it is a mod only
+    /// when the `%` is apart from its value.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let eval = |input| Expression::root(input).unwrap().eval().unwrap();
+    ///
+    /// assert_eq!(eval("200 + 10%"), 220.0);
+    /// assert_eq!(eval("10 % -3"), 1.0);
+    /// ```
+    Percent(Box<Expression>),
+    Call {
+        name: String,
+        args: Vec<Expression>,
+    },
     Group(Group),
+    Seq(Vec<Expression>),
 }
 
 impl Expression {
+    /// Parses `input` into a tree ready to evaluate. Powers and roots group
+    /// from the right and bind tighter than a leading minus, like they do on
+    /// paper. A root takes its degree first: `n ~ x` is the n-th root of x and
+    /// `√x` is `2 ~ x`.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let eval = |input| Expression::root(input).unwrap().eval().unwrap();
+    ///
+    /// assert_eq!(eval("2^3^2"), 512.0);
+    /// assert_eq!(eval("2^2^3"), 256.0);
+    /// assert_eq!(eval("2^3^2 * 2"), 1024.0);
+    /// assert_eq!(eval("(2^3)^2"), 64.0);
+    /// assert_eq!(eval("2 ~ 2 ~ 256"), 4.0);
+    ///
+    /// assert_eq!(eval("-2^2"), -4.0);
+    /// assert_eq!(eval("(-2)^2"), 4.0);
+    /// assert_eq!(eval("-2^2^2"), -16.0);
+    /// assert_eq!(eval("2^-2"), 0.25);
+    ///
+    /// assert_eq!(eval("2~9"), 3.0);
+    /// assert_eq!(eval("3~27"), 3.0);
+    /// assert_eq!(eval("3~-8"), -2.0);
+    /// assert_eq!(eval("√16"), 4.0);
+    ///
+    /// let err = |input| Expression::root(input).unwrap().eval().unwrap_err().to_string();
+    ///
+    /// assert_eq!(err("2~-4"), "2 ~ -4: a negative number has no even root");
+    /// assert_eq!(err("√-4"), "2 ~ -4: a negative number has no even root");
+    /// assert_eq!(
+    ///     err("(-8)^0.5"),
+    ///     "-8 ^ 0.5: a negative number has no real power that isn't whole"
+    /// );
+    /// assert_eq!(eval("(-8)^3"), -512.0);
+    /// assert_eq!(eval("(-2)^3"), -8.0);
+    /// assert_eq!(eval("1.0000001^3"), 1.0000001f64.powi(3));
+    /// assert_eq!(eval("2^0.5"), 2f64.powf(0.5));
+    /// ```
     pub fn root(input: &str) -> Result<Expression> {
-        let body = tokenize(input)?;
-        Ok(Expression::Group(Group { body }))
+        Expression::root_with_max_depth(input, MAX_DEPTH)
+    }
+
+    /// Like `root`, with a different limit on how deeply `input` may nest.
+    /// The depth is estimated from the input before any tree is built.
+    /// Parentheses, powers and prefix operators each add to it, but a run of
+    /// left-associative operators like a long sum counts once, since building,
+    /// evaluating and dropping walk it in a loop. Anything over `max_depth` is
+    /// a [`CalcError::TooDeep`] instead of a stack overflow.
+    ///
+    /// ```
+    /// use nac::{CalcError, Expression, MAX_DEPTH};
+    ///
+    /// let nested = |n| format!("{}1{}", "(".repeat(n), ")".repeat(n));
+    /// let too_deep = Err(CalcError::TooDeep { limit: MAX_DEPTH });
+    ///
+    /// assert_eq!(Expression::root(&nested(100))?.eval()?, 1.0);
+    /// assert_eq!(Expression::root(&nested(50_000)), too_deep);
+    /// assert_eq!(Expression::root(&vec!["2"; 50_000].join("^")), too_deep);
+    /// assert_eq!(Expression::root(&"-".repeat(50_000)), too_deep);
+    ///
+    /// let sum = vec!["1"; 10_000].join(" + ");
+    /// assert_eq!(Expression::root(&sum)?.eval()?, 10_000.0);
+    ///
+    /// assert_eq!(
+    ///     Expression::root_with_max_depth(&nested(100), 50),
+    ///     Err(CalcError::TooDeep { limit: 50 })
+    /// );
+    /// # Ok::<(), CalcError>(())
+    /// ```
+    pub fn root_with_max_depth(input: &str, max_depth: usize) -> Result<Expression> {
+        let mut group = parse(input, max_depth)?;
+        group.validate()?;
+        group.resolve()?;
+
+        Ok(Expression::Group(group))
+    }
+
+    /// Looks for every problem in `input` rather than only the first, as
+    /// `root` does. Unexpected characters, malformed numbers and unbalanced
+    /// parentheses are all collected in one pass. Only when there are none is
+    /// the structure checked, so a bad token doesn't also show up as a missing
+    /// operand or operator.
+    ///
+    /// ```
+    /// use nac::{CalcError, Expression};
+    ///
+    /// let starts = |input| -> Vec<usize> {
+    ///     let errors = Expression::check(input).unwrap_err();
+    ///     errors.iter().map(|e| e.span().unwrap().start).collect()
+    /// };
+    ///
+    /// assert_eq!(Expression::check("2 * (3 + 4)"), Ok(()));
+    ///
+    /// let errors = Expression::check("2 $ 3 + 1.2.3").unwrap_err();
+    /// assert!(matches!(
+    ///     errors.as_slice(),
+    ///     [CalcError::UnexpectedChar { ch: '$', .. }, CalcError::InvalidNumber { .. }]
+    /// ));
+    ///
+    /// let errors = Expression::check("(2 @@@ 3) + 0b12) * (4").unwrap_err();
+    /// assert!(matches!(
+    ///     errors.as_slice(),
+    ///     [
+    ///         CalcError::UnexpectedChar { ch: '@', .. },
+    ///         CalcError::InvalidNumber { .. },
+    ///         CalcError::UnmatchedParen { .. },
+    ///         CalcError::UnclosedParen { .. },
+    ///     ]
+    /// ));
+    /// assert_eq!(starts("(2 @@@ 3) + 0b12) * (4"), [3, 15, 16, 20]);
+    ///
+    /// // With the tokens fine, the first structural problem is reported.
+    /// assert!(matches!(
+    ///     Expression::check("2 * * 3").unwrap_err().as_slice(),
+    ///     [CalcError::UnexpectedOperator { .. }]
+    /// ));
+    /// ```
+    pub fn check(input: &str) -> std::result::Result<(), Vec<CalcError>> {
+        let (tokens, mut errors) = lexer::lex_recovering(input);
+        check_parens(&tokens, &mut errors);
+
+        if errors.is_empty() {
+            return Expression::root(input).map(|_| ()).map_err(|e| vec![e]);
+        }
+
+        errors.sort_by_key(|e| e.span().map(|span| span.start));
+        Err(errors)
     }
 
     pub fn root_with_prev(input: &str, prev: f64) -> Result<Expression> {
-        let mut body = tokenize(input)?;
+        let mut group = parse(input, MAX_DEPTH)?;
 
-        match &body[0] {
-            Expression::Add(_) | Expression::Sub(_) | Expression::Mul(_) | Expression::Div(_) => {
-                body.insert(0, Expression::Unit(prev.to_string()));
+        match group.body.first() {
+            Some(
+                Expression::Add(_) | Expression::Sub(_) | Expression::Mul(_) | Expression::Div(_),
+            ) => {
+                group.insert(0, Expression::Unit(prev.to_string()), Span::new(0, 0));
+            }
+            Some(Expression::Neg(None)) => {
+                group.body[0] = Expression::Sub(OpParams::default());
+                group.insert(0, Expression::Unit(prev.to_string()), Span::new(0, 0));
+            }
+            Some(Expression::Pos(None)) => {
+                group.body[0] = Expression::Add(OpParams::default());
+                group.insert(0, Expression::Unit(prev.to_string()), Span::new(0, 0));
             }
             _ => {}
         }
 
-        Ok(Expression::Group(Group { body }))
+        group.validate()?;
+        group.resolve()?;
+
+        Ok(Expression::Group(group))
+    }
+
+    /// Writes the expression in reverse Polish notation. Unary minus is `neg`
+    /// and, as on RPN calculators, `%` is a percentage while modulo is `mod`.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let rpn = |input| Expression::root(input).unwrap().to_rpn();
+    ///
+    /// assert_eq!(rpn("3 + 4 * 2"), "3 4 2 * +");
+    /// assert_eq!(rpn("2^3^2"), "2 3 2 ^ ^");
+    /// assert_eq!(rpn("-(3~27) % 2"), "3 27 ~ neg 2 mod");
+    /// ```
+    pub fn to_rpn(&self) -> String {
+        let mut out = vec![];
+        self.push_rpn(&mut out);
+        out.join(" ")
+    }
+
+    fn push_rpn(&self, out: &mut Vec<String>) {
+        match self {
+            Expression::Unit(value) => out.push(value.clone()),
+            Expression::Var(name) => out.push(name.clone()),
+            Expression::Group(group) => group.body.iter().for_each(|exp| exp.push_rpn(out)),
+            Expression::Seq(statements) => {
+                for (idx, statement) in statements.iter().enumerate() {
+                    if idx > 0 {
+                        out.push(";".to_string());
+                    }
+                    statement.push_rpn(out);
+                }
+            }
+            Expression::Call { name, args } => {
+                args.iter().for_each(|arg| arg.push_rpn(out));
+                out.push(name.clone());
+            }
+            Expression::Pos(Some(operand)) => operand.push_rpn(out),
+            Expression::Neg(Some(operand))
+            | Expression::Not(Some(operand))
+            | Expression::Fact(operand)
+            | Expression::Abs(operand)
+            | Expression::Percent(operand) => {
+                operand.push_rpn(out);
+                let op = match self {
+                    Expression::Neg(_) => "neg",
+                    Expression::Not(_) => "not",
+                    Expression::Fact(_) => "!",
+                    Expression::Abs(_) => "abs",
+                    _ => "%",
+                };
+                out.push(op.to_string());
+            }
+            _ => {
+                if let Some(OpParams {
+                    lhs: Some(lhs),
+                    rhs: Some(rhs),
+                }) = self.params()
+                {
+                    lhs.push_rpn(out);
+                    rhs.push_rpn(out);
+                }
+
+                let op = match self {
+                    Expression::Mod(_) => "mod",
+                    _ => self.symbol().unwrap_or_default(),
+                };
+                out.push(op.to_string());
+            }
+        }
+    }
+
+    /// Writes the tree as an S-expression, one list per operator, so the
+    /// grouping the parser chose is spelled out. Parentheses from the input
+    /// leave no trace once they have done their job.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let sexpr = |input| Expression::root(input).unwrap().to_sexpr();
+    ///
+    /// assert_eq!(sexpr("1+2*3"), "(+ 1 (* 2 3))");
+    /// assert_eq!(sexpr("(1+2)*3"), "(* (+ 1 2) 3)");
+    /// assert_eq!(sexpr("2^3^2"), "(^ 2 (^ 3 2))");
+    /// assert_eq!(sexpr("-2^2"), "(- (^ 2 2))");
+    /// assert_eq!(sexpr("3~27 + √16"), "(+ (~ 3 27) (~ 2 16))");
+    /// assert_eq!(sexpr("max(1, 2) + 5%"), "(+ (max 1 2) (% 5))");
+    /// assert_eq!(sexpr("x = 2; x!"), "(seq (= x 2) (! x))");
+    /// ```
+    pub fn to_sexpr(&self) -> String {
+        let exp = self.ungrouped();
+        if let Expression::Unit(_) | Expression::Var(_) = exp {
+            return exp.label();
+        }
+
+        let mut out = format!("({}", exp.label());
+        for child in exp.children() {
+            out.push(' ');
+            out.push_str(&child.to_sexpr());
+        }
+        out.push(')');
+        out
+    }
+
+    /// Writes the tree as a Graphviz digraph, for `dot -Tpng` and friends.
+    /// Nodes are numbered in the order [`Expression::iter`] would give them,
+    /// skipping the groups that [`Expression::to_sexpr`] leaves out as well.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let dot = Expression::root("2*(3+4)^2").unwrap().to_dot();
+    ///
+    /// assert!(dot.starts_with("digraph {\n    n0 [label=\"*\"];\n"));
+    /// for label in ["\"*\"", "\"^\"", "\"+\"", "\"3\"", "\"4\""] {
+    ///     assert!(dot.contains(&format!("[label={label}]")));
+    /// }
+    /// assert_eq!(dot.matches("[label=").count(), 7);
+    /// assert_eq!(dot.matches(" -> ").count(), 6);
+    /// assert!(dot.contains("n0 -> n2;"));
+    ///
+    /// let dot = Expression::root("sqrt(16) + x").unwrap().to_dot();
+    /// assert!(dot.contains("[label=\"sqrt\"]"));
+    /// assert_eq!(dot.matches(" -> ").count(), 3);
+    /// ```
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph {\n");
+        self.push_dot(&mut out, &mut 0);
+        out.push_str("}\n");
+        out
+    }
+
+    fn push_dot(&self, out: &mut String, next: &mut usize) -> usize {
+        let exp = self.ungrouped();
+        let id = *next;
+        *next += 1;
+
+        out.push_str(&format!("    n{id} [label={:?}];\n", exp.label()));
+        for child in exp.children() {
+            let child = child.push_dot(out, next);
+            out.push_str(&format!("    n{id} -> n{child};\n"));
+        }
+        id
+    }
+
+    fn ungrouped(&self) -> &Expression {
+        match self {
+            Expression::Group(group) if group.body.len() == 1 => group.body[0].ungrouped(),
+            _ => self,
+        }
+    }
+
+    fn label(&self) -> String {
+        let label = match self {
+            Expression::Unit(value) => value,
+            Expression::Var(name) | Expression::Call { name, .. } => name,
+            Expression::Group(_) => "group",
+            Expression::Seq(_) => "seq",
+            Expression::Fact(_) => "!",
+            Expression::Abs(_) => "abs",
+            Expression::Percent(_) => "%",
+            _ => self.symbol().unwrap_or_default(),
+        };
+
+        label.to_string()
+    }
+
+    /// Evaluates the expression without touching it, so a parsed expression
+    /// can be evaluated any number of times, from any number of threads.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let exp = Expression::root("2 * (3 + 4)").unwrap();
+    /// assert_eq!(exp.eval().unwrap(), 14.0);
+    /// assert_eq!(exp.eval().unwrap(), 14.0);
+    ///
+    /// std::thread::scope(|s| {
+    ///     s.spawn(|| assert_eq!(exp.eval().unwrap(), 14.0));
+    ///     s.spawn(|| assert_eq!(exp.eval().unwrap(), 14.0));
+    /// });
+    /// ```
+    pub fn eval(&self) -> Result<f64> {
+        self.eval_with(&HashMap::new())
+    }
+
+    /// Evaluates with `bindings` giving the values of variables. Variables
+    /// that are neither bound here, nor constants, nor assigned in the
+    /// expression itself are all reported together, before anything runs.
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use nac::{CalcError, Expression};
+    ///
+    /// let quadratic = Expression::root("x^2 + 3*x + 1").unwrap();
+    /// let bindings = HashMap::from([("x".to_string(), 2.0)]);
+    /// assert_eq!(quadratic.eval_with(&bindings), Ok(11.0));
+    ///
+    /// let err = Expression::root("x * y + x").unwrap().eval().unwrap_err();
+    /// assert_eq!(err, CalcError::UnboundVariables { names: vec!["x".into(), "y".into()] });
+    /// assert_eq!(err.to_string(), "unknown variables: x, y");
+    ///
+    /// // Binding a variable leaves the constants alone.
+    /// let circle = Expression::root("pi * r^2 * e / e").unwrap();
+    /// assert_eq!(circle.free_variables(), ["r"]);
+    /// let bindings = HashMap::from([("r".to_string(), 1.0)]);
+    /// assert_eq!(circle.eval_with(&bindings), Ok(std::f64::consts::PI));
+    /// ```
+    pub fn eval_with(&self, bindings: &HashMap<String, f64>) -> Result<f64> {
+        let names: Vec<String> = self
+            .free_variables()
+            .into_iter()
+            .filter(|name| !bindings.contains_key(*name))
+            .map(str::to_string)
+            .collect();
+
+        if !names.is_empty() {
+            return Err(CalcError::UnboundVariables { names });
+        }
+
+        self.eval_with_env(&mut bindings.clone())
+    }
+
+    /// The variables the expression needs a value for, in the order they first
+    /// appear. Constants and variables the expression assigns are left out.
+    pub fn free_variables(&self) -> Vec<&str> {
+        let assigned: Vec<&str> = self
+            .iter()
+            .filter_map(|exp| match exp {
+                Expression::Assign(OpParams { lhs: Some(lhs), .. }) => match lhs.as_ref() {
+                    Expression::Var(name) => Some(name.as_str()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+
+        let mut names = vec![];
+        for exp in self.iter() {
+            if let Expression::Var(name) = exp {
+                let name = name.as_str();
+                if constant(name).is_none() && !assigned.contains(&name) && !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+
+    pub fn eval_ref(&self) -> Result<f64> {
+        self.eval()
+    }
+
+    pub fn eval_with_env(&self, env: &mut HashMap<String, f64>) -> Result<f64> {
+        self.eval_with_env_and_mode(env, AngleMode::default())
+    }
+
+    pub fn eval_with_mode(&self, mode: AngleMode) -> Result<f64> {
+        self.eval_with_env_and_mode(&mut HashMap::new(), mode)
+    }
+
+    pub fn eval_with_env_and_mode(
+        &self,
+        env: &mut HashMap<String, f64>,
+        mode: AngleMode,
+    ) -> Result<f64> {
+        self.evaluate(env, mode, EvalOptions::default())
+    }
+
+    pub fn eval_with_options(&self, options: EvalOptions) -> Result<f64> {
+        self.evaluate(&mut HashMap::new(), AngleMode::default(), options)
+    }
+
+    fn evaluate(
+        &self,
+        env: &mut HashMap<String, f64>,
+        mode: AngleMode,
+        options: EvalOptions,
+    ) -> Result<f64> {
+        // A run of left-associative operators like `1 + 2 + ... + n` nests once
+        // per term on the left, so it is walked in a loop, innermost first,
+        // instead of recursing down it.
+        let mut run = vec![];
+        let mut exp = self;
+        while let Some(lhs) = exp.chained_lhs() {
+            run.push(exp);
+            exp = lhs;
+        }
+
+        let mut value = exp.evaluate_with_lhs(None, env, mode, options)?;
+        for exp in run.into_iter().rev() {
+            value = exp.evaluate_with_lhs(Some(value), env, mode, options)?;
+        }
+        Ok(value)
     }
 
-    pub fn eval(&mut self) -> Result<f64> {
+    // Evaluates one node, taking the value of its left operand from `known`
+    // when `evaluate` has already worked it out.
+    fn evaluate_with_lhs(
+        &self,
+        known: Option<f64>,
+        env: &mut HashMap<String, f64>,
+        mode: AngleMode,
+        options: EvalOptions,
+    ) -> Result<f64> {
         match self {
             Expression::Unit(val) => {
-                let value: f64 = val.parse()?;
-                return Ok(value);
+                let value = val.parse().map_err(|_| CalcError::ParseNumber {
+                    literal: val.clone(),
+                })?;
+                if options.exact_integers && is_inexact_integer(val) {
+                    return Err(CalcError::InexactInteger {
+                        literal: val.clone(),
+                    });
+                }
+                finite(value, options, || val.clone())
+            }
+            Expression::Var(name) => {
+                let value = env
+                    .get(name)
+                    .copied()
+                    .or_else(|| constant(name))
+                    .ok_or_else(|| CalcError::UnknownVariable { name: name.clone() })?;
+                finite(value, options, || name.clone())
+            }
+            Expression::Assign(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            }) => {
+                let Expression::Var(name) = lhs.as_ref() else {
+                    return Err(CalcError::NotAssignable {
+                        target: lhs.to_string(),
+                    });
+                };
+
+                let value = rhs.evaluate(env, mode, options)?;
+                env.insert(name.clone(), value);
+                Ok(value)
             }
             Expression::Add(OpParams {
                 lhs: Some(lhs),
                 rhs: Some(rhs),
             }) => {
-                let lhs = lhs.eval()?;
-                let rhs = rhs.eval()?;
-                return Ok(lhs + rhs);
+                let lhs = known.map_or_else(|| lhs.evaluate(env, mode, options), Ok)?;
+                let rhs = rhs.eval_relative_to(lhs, env, mode, options)?;
+                finite(lhs + rhs, options, || format!("{lhs} + {rhs}"))
             }
             Expression::Sub(OpParams {
                 lhs: Some(lhs),
                 rhs: Some(rhs),
             }) => {
-                let lhs = lhs.eval()?;
-                let rhs = rhs.eval()?;
-                return Ok(lhs - rhs);
+                let lhs = known.map_or_else(|| lhs.evaluate(env, mode, options), Ok)?;
+                let rhs = rhs.eval_relative_to(lhs, env, mode, options)?;
+                finite(lhs - rhs, options, || format!("{lhs} - {rhs}"))
             }
             Expression::Mul(OpParams {
                 lhs: Some(lhs),
                 rhs: Some(rhs),
             }) => {
-                let lhs = lhs.eval()?;
-                let rhs = rhs.eval()?;
-                return Ok(lhs * rhs);
+                let lhs = known.map_or_else(|| lhs.evaluate(env, mode, options), Ok)?;
+                let rhs = rhs.evaluate(env, mode, options)?;
+                finite(lhs * rhs, options, || format!("{lhs} * {rhs}"))
             }
             Expression::Div(OpParams {
                 lhs: Some(lhs),
                 rhs: Some(rhs),
             }) => {
-                let lhs = lhs.eval()?;
-                let rhs = rhs.eval()?;
-                return Ok(lhs / rhs);
+                let lhs = known.map_or_else(|| lhs.evaluate(env, mode, options), Ok)?;
+                let rhs = rhs.evaluate(env, mode, options)?;
+                if !options.allow_non_finite && rhs == 0.0 {
+                    return Err(CalcError::DivByZero {
+                        expression: self.to_string(),
+                    });
+                }
+                finite(lhs / rhs, options, || format!("{lhs} / {rhs}"))
+            }
+            Expression::IntDiv(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            }) => {
+                let lhs = known.map_or_else(|| lhs.evaluate(env, mode, options), Ok)?;
+                let rhs = rhs.evaluate(env, mode, options)?;
+                if rhs == 0.0 {
+                    return Err(CalcError::DivByZero {
+                        expression: self.to_string(),
+                    });
+                }
+                finite((lhs / rhs).floor(), options, || format!("{lhs} // {rhs}"))
             }
             Expression::Mod(OpParams {
                 lhs: Some(lhs),
                 rhs: Some(rhs),
             }) => {
-                let lhs = lhs.eval()?;
-                let rhs = rhs.eval()?;
-                return Ok(lhs % rhs);
+                let lhs = known.map_or_else(|| lhs.evaluate(env, mode, options), Ok)?;
+                let rhs = rhs.evaluate(env, mode, options)?;
+                if !options.allow_non_finite && rhs == 0.0 {
+                    return Err(CalcError::DivByZero {
+                        expression: self.to_string(),
+                    });
+                }
+                finite(lhs % rhs, options, || format!("{lhs} % {rhs}"))
             }
 
             Expression::Pow(OpParams {
                 lhs: Some(lhs),
                 rhs: Some(rhs),
             }) => {
-                let lhs = lhs.eval()?;
-                let rhs = rhs.eval()?;
-                return Ok(lhs.powf(rhs));
+                let lhs = lhs.evaluate(env, mode, options)?;
+                let rhs = rhs.evaluate(env, mode, options)?;
+                if lhs == 0.0 && rhs == 0.0 {
+                    match options.zero_pow_zero {
+                        ZeroPowZero::One => {}
+                        ZeroPowZero::Nan => return finite(f64::NAN, options, || "0 ^ 0".into()),
+                        ZeroPowZero::Error => {
+                            return Err(CalcError::Math {
+                                message: "0 ^ 0 is undefined".to_string(),
+                            })
+                        }
+                    }
+                }
+                let power = if rhs.fract() == 0.0 && rhs.abs() <= f64::from(i32::MAX) {
+                    lhs.powi(rhs as i32)
+                } else {
+                    lhs.powf(rhs)
+                };
+                if power.is_nan() && !lhs.is_nan() && !rhs.is_nan() {
+                    return Err(CalcError::NoRealResult {
+                        message: format!(
+                            "{lhs} ^ {rhs}: a negative number has no real power that isn't whole"
+                        ),
+                    });
+                }
+                finite(power, options, || format!("{lhs} ^ {rhs}"))
             }
             Expression::Root(OpParams {
                 lhs: Some(lhs),
                 rhs: Some(rhs),
             }) => {
-                let lhs = lhs.eval()?;
-                let rhs = rhs.eval()?;
-                return Ok(rhs.powf(1.0 / lhs));
+                // `n ~ x` is the n-th root of x, so `3 ~ 27` is 3.
+                let lhs = lhs.evaluate(env, mode, options)?;
+                let rhs = rhs.evaluate(env, mode, options)?;
+                if lhs == 0.0 && options.zero_root_error {
+                    return Err(CalcError::Math {
+                        message: format!("0 ~ {rhs}: a root needs a degree other than 0"),
+                    });
+                }
+                if rhs < 0.0 && (lhs.fract() != 0.0 || lhs % 2.0 == 0.0) {
+                    let problem = if lhs.fract() != 0.0 {
+                        "needs a whole degree"
+                    } else {
+                        "has no even root"
+                    };
+                    return Err(CalcError::NoRealResult {
+                        message: format!("{lhs} ~ {rhs}: a negative number {problem}"),
+                    });
+                }
+                let root = if rhs < 0.0 {
+                    -(-rhs).powf(1.0 / lhs)
+                } else {
+                    rhs.powf(1.0 / lhs)
+                };
+                finite(root, options, || format!("{lhs} ~ {rhs}"))
             }
-
-            Expression::Group(group) => {
-                group.resolve()?;
-
-                if group.body.len() != 1 {
-                    return Err(anyhow!("unresolved expression {group:?}"));
+            Expression::Shl(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            }) => {
+                let lhs = to_integer(
+                    known.map_or_else(|| lhs.evaluate(env, mode, options), Ok)?,
+                    "<<",
+                )?;
+                let rhs = to_shift_amount(rhs.evaluate(env, mode, options)?, "<<")?;
+                let shifted = lhs << rhs;
+                if shifted >> rhs != lhs {
+                    return Err(CalcError::Math {
+                        message: format!("{lhs} << {rhs} overflows 64 bits"),
+                    });
                 }
-
-                return group.body[0].eval();
+                Ok(shifted as f64)
             }
-            _ => {
-                return Err(anyhow!("unhandled expression {self:?}"));
+            Expression::Shr(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            }) => {
+                let lhs = to_integer(
+                    known.map_or_else(|| lhs.evaluate(env, mode, options), Ok)?,
+                    ">>",
+                )?;
+                let rhs = to_shift_amount(rhs.evaluate(env, mode, options)?, ">>")?;
+                Ok((lhs >> rhs) as f64)
+            }
+            Expression::BitAnd(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            }) => {
+                let lhs = to_integer(
+                    known.map_or_else(|| lhs.evaluate(env, mode, options), Ok)?,
+                    "&",
+                )?;
+                let rhs = to_integer(rhs.evaluate(env, mode, options)?, "&")?;
+                Ok((lhs & rhs) as f64)
+            }
+            Expression::BitXor(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            }) => {
+                let lhs = to_integer(
+                    known.map_or_else(|| lhs.evaluate(env, mode, options), Ok)?,
+                    "^^",
+                )?;
+                let rhs = to_integer(rhs.evaluate(env, mode, options)?, "^^")?;
+                Ok((lhs ^ rhs) as f64)
+            }
+            Expression::BitOr(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            }) => {
+                let lhs = to_integer(
+                    known.map_or_else(|| lhs.evaluate(env, mode, options), Ok)?,
+                    "|",
+                )?;
+                let rhs = to_integer(rhs.evaluate(env, mode, options)?, "|")?;
+                Ok((lhs | rhs) as f64)
+            }
+            Expression::Lt(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            }) => {
+                let lhs = known.map_or_else(|| lhs.evaluate(env, mode, options), Ok)?;
+                let rhs = rhs.evaluate(env, mode, options)?;
+                Ok(f64::from(lhs < rhs))
+            }
+            Expression::Le(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            }) => {
+                let lhs = known.map_or_else(|| lhs.evaluate(env, mode, options), Ok)?;
+                let rhs = rhs.evaluate(env, mode, options)?;
+                Ok(f64::from(lhs <= rhs))
+            }
+            Expression::Gt(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            }) => {
+                let lhs = known.map_or_else(|| lhs.evaluate(env, mode, options), Ok)?;
+                let rhs = rhs.evaluate(env, mode, options)?;
+                Ok(f64::from(lhs > rhs))
+            }
+            Expression::Ge(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            }) => {
+                let lhs = known.map_or_else(|| lhs.evaluate(env, mode, options), Ok)?;
+                let rhs = rhs.evaluate(env, mode, options)?;
+                Ok(f64::from(lhs >= rhs))
+            }
+            Expression::Eq(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            }) => {
+                let lhs = known.map_or_else(|| lhs.evaluate(env, mode, options), Ok)?;
+                let rhs = rhs.evaluate(env, mode, options)?;
+                // exact comparison, so `0.1 + 0.2 == 0.3` is false like it is for any f64
+                Ok(f64::from(lhs == rhs))
+            }
+            Expression::Ne(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            }) => {
+                let lhs = known.map_or_else(|| lhs.evaluate(env, mode, options), Ok)?;
+                let rhs = rhs.evaluate(env, mode, options)?;
+                Ok(f64::from(lhs != rhs))
+            }
+            Expression::And(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            }) => {
+                if known.map_or_else(|| lhs.evaluate(env, mode, options), Ok)? == 0.0 {
+                    return Ok(0.0);
+                }
+                let rhs = rhs.evaluate(env, mode, options)?;
+                Ok(f64::from(rhs != 0.0))
+            }
+            Expression::Or(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            }) => {
+                if known.map_or_else(|| lhs.evaluate(env, mode, options), Ok)? != 0.0 {
+                    return Ok(1.0);
+                }
+                let rhs = rhs.evaluate(env, mode, options)?;
+                Ok(f64::from(rhs != 0.0))
+            }
+            Expression::Not(Some(inner)) => {
+                let inner = inner.evaluate(env, mode, options)?;
+                Ok(f64::from(inner == 0.0))
+            }
+            Expression::Neg(Some(inner)) => Ok(-inner.evaluate(env, mode, options)?),
+            Expression::Pos(Some(inner)) => inner.evaluate(env, mode, options),
+            Expression::Fact(inner) => {
+                let inner = inner.evaluate(env, mode, options)?;
+                finite(factorial(inner)?, options, || format!("{inner}!"))
+            }
+            Expression::Abs(inner) => Ok(inner.evaluate(env, mode, options)?.abs()),
+            Expression::Percent(inner) => Ok(inner.evaluate(env, mode, options)? / 100.0),
+            Expression::Call { name, args } if name == "deriv" => {
+                let [body, var] = args.as_slice() else {
+                    return Err(CalcError::ArgumentCount {
+                        name: name.clone(),
+                        expected: "2 arguments".to_string(),
+                        got: args.len(),
+                    });
+                };
+                let Expression::Var(var) = var.ungrouped() else {
+                    return Err(CalcError::Math {
+                        message: format!("deriv takes the variable second, got {var}"),
+                    });
+                };
+                body.derivative(var)?.evaluate(env, mode, options)
             }
+            Expression::Call { name, args } => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.evaluate(env, mode, options))
+                    .collect::<Result<Vec<f64>>>()?;
+                finite(call_function(name, &args, mode)?, options, || {
+                    let args: Vec<String> = args.iter().map(f64::to_string).collect();
+                    format!("{name}({})", args.join(", "))
+                })
+            }
+
+            Expression::Group(group) => match group.body.as_slice() {
+                [exp] => exp.evaluate(env, mode, options),
+                [] => Err(CalcError::EmptyExpression),
+                _ => Err(CalcError::Unresolved {
+                    span: Some(group.spans[1]),
+                }),
+            },
+            Expression::Seq(statements) => {
+                let mut value = 0.0;
+                for statement in statements {
+                    value = statement.evaluate(env, mode, options)?;
+                }
+                Ok(value)
+            }
+            _ => Err(CalcError::Unresolved { span: None }),
         }
     }
-}
-
-#[derive(Debug, Default)]
-pub struct Group {
-    body: Vec<Expression>,
-}
 
-impl Group {
-    fn parse_params(&mut self, mut exp_idx: usize) -> Result<()> {
-        if exp_idx + 1 == self.body.len() {
-            return Err(anyhow!("missing right hand side"));
+    // `200 + 10%` adds ten percent of 200 rather than 0.1.
+    fn eval_relative_to(
+        &self,
+        base: f64,
+        env: &mut HashMap<String, f64>,
+        mode: AngleMode,
+        options: EvalOptions,
+    ) -> Result<f64> {
+        match self {
+            Expression::Percent(inner) => Ok(base * inner.evaluate(env, mode, options)? / 100.0),
+            _ => self.evaluate(env, mode, options),
         }
+    }
 
-        let rhs = self.body.remove(exp_idx + 1);
+    fn is_value(&self) -> bool {
+        matches!(
+            self,
+            Expression::Unit(_)
+                | Expression::Var(_)
+                | Expression::Group(_)
+                | Expression::Fact(_)
+                | Expression::Abs(_)
+                | Expression::Percent(_)
+                | Expression::Call { .. }
+                | Expression::Neg(Some(_))
+                | Expression::Pos(Some(_))
+                | Expression::Not(Some(_))
+                | Expression::Pow(OpParams {
+                    lhs: Some(_),
+                    rhs: Some(_)
+                })
+        )
+    }
 
-        let lhs: Option<Expression>;
-        if exp_idx == 0 {
-            lhs = None;
-        } else {
-            exp_idx -= 1;
-            lhs = Some(self.body.remove(exp_idx));
-        };
+    fn is_unresolved_prefix(&self) -> bool {
+        self.is_unresolved_unary() || matches!(self, Expression::Not(None))
+    }
 
-        let exp = &mut self.body[exp_idx];
+    fn operator_symbol(&self) -> Option<&'static str> {
+        if self.is_value() {
+            return None;
+        }
 
-        match exp {
-            Expression::Add(params) | Expression::Sub(params) => {
-                params.lhs = Some(Box::new(lhs.unwrap_or(Expression::Unit("0".to_string()))));
-                params.rhs = Some(Box::new(rhs));
-            }
-            Expression::Mul(params)
-            | Expression::Div(params)
-            | Expression::Mod(params)
-            | Expression::Pow(params)
-            | Expression::Root(params) => {
-                if lhs.is_none() {
-                    return Err(anyhow!("missing left hand side"));
-                }
+        self.symbol()
+    }
 
-                let lhs = lhs.unwrap();
-                params.lhs = Some(Box::new(lhs));
-                params.rhs = Some(Box::new(rhs));
-            }
-            _ => return Err(anyhow!("unexpected expression {exp:?}")),
-        }
+    fn symbol(&self) -> Option<&'static str> {
+        let symbol = match self {
+            Expression::Neg(_) => "-",
+            Expression::Pos(_) => "+",
+            Expression::Not(_) => "not",
+            Expression::Root(OpParams {
+                lhs: Some(_),
+                rhs: None,
+            }) => "√",
+            Expression::Add(_) => "+",
+            Expression::Sub(_) => "-",
+            Expression::Mul(_) => "*",
+            Expression::Div(_) => "/",
+            Expression::IntDiv(_) => "//",
+            Expression::Mod(_) => "%",
+            Expression::Pow(_) => "^",
+            Expression::Root(_) => "~",
+            Expression::Shl(_) => "<<",
+            Expression::Shr(_) => ">>",
+            Expression::BitAnd(_) => "&",
+            Expression::BitXor(_) => "^^",
+            Expression::BitOr(_) => "|",
+            Expression::Lt(_) => "<",
+            Expression::Le(_) => "<=",
+            Expression::Gt(_) => ">",
+            Expression::Ge(_) => ">=",
+            Expression::Eq(_) => "==",
+            Expression::Ne(_) => "!=",
+            Expression::And(_) => "and",
+            Expression::Or(_) => "or",
+            Expression::Assign(_) => "=",
+            _ => return None,
+        };
 
-        Ok(())
+        Some(symbol)
     }
 
-    fn resolve(&mut self) -> Result<()> {
-        while let Some(idx) = self.body.iter().position(|e| match e {
-            Expression::Pow(params) => params.lhs.is_none() || params.lhs.is_none(),
-            Expression::Root(params) => params.lhs.is_none() || params.lhs.is_none(),
-            _ => false,
-        }) {
-            self.parse_params(idx)?;
+    /// The expressions directly below this one, left to right.
+    pub fn children(&self) -> Vec<&Expression> {
+        match self {
+            Expression::Group(group) => group.body.iter().collect(),
+            Expression::Abs(inner)
+            | Expression::Fact(inner)
+            | Expression::Percent(inner)
+            | Expression::Neg(Some(inner))
+            | Expression::Pos(Some(inner))
+            | Expression::Not(Some(inner)) => vec![inner],
+            Expression::Call { args, .. } | Expression::Seq(args) => args.iter().collect(),
+            _ => match self.params() {
+                Some(params) => [&params.lhs, &params.rhs]
+                    .into_iter()
+                    .flatten()
+                    .map(|operand| operand.as_ref())
+                    .collect(),
+                None => vec![],
+            },
         }
+    }
 
-        while let Some(idx) = self.body.iter().position(|e| match e {
-            Expression::Mul(params) => params.lhs.is_none() || params.lhs.is_none(),
-            Expression::Div(params) => params.lhs.is_none() || params.lhs.is_none(),
-            Expression::Mod(params) => params.lhs.is_none() || params.lhs.is_none(),
-            _ => false,
-        }) {
-            self.parse_params(idx)?;
+    fn children_mut(&mut self) -> Vec<&mut Expression> {
+        match self {
+            Expression::Group(group) => group.body.iter_mut().collect(),
+            Expression::Abs(inner)
+            | Expression::Fact(inner)
+            | Expression::Percent(inner)
+            | Expression::Neg(Some(inner))
+            | Expression::Pos(Some(inner))
+            | Expression::Not(Some(inner)) => vec![inner],
+            Expression::Call { args, .. } | Expression::Seq(args) => args.iter_mut().collect(),
+            _ => match self.params_mut() {
+                Some(params) => [&mut params.lhs, &mut params.rhs]
+                    .into_iter()
+                    .flatten()
+                    .map(|operand| operand.as_mut())
+                    .collect(),
+                None => vec![],
+            },
         }
+    }
 
-        while let Some(idx) = self.body.iter().position(|e| match e {
-            Expression::Add(params) => params.lhs.is_none() || params.lhs.is_none(),
-            Expression::Sub(params) => params.lhs.is_none() || params.lhs.is_none(),
-            _ => false,
-        }) {
-            self.parse_params(idx)?;
+    /// Calls `f` on this expression and then on everything below it, parents
+    /// before children.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let exp = Expression::root("1+2*3").unwrap();
+    ///
+    /// let mut units = vec![];
+    /// exp.visit(&mut |node| {
+    ///     if let Expression::Unit(value) = node {
+    ///         units.push(value.clone());
+    ///     }
+    /// });
+    /// assert_eq!(units, ["1", "2", "3"]);
+    /// ```
+    pub fn visit(&self, f: &mut impl FnMut(&Expression)) {
+        f(self);
+        for child in self.children() {
+            child.visit(f);
         }
-        Ok(())
     }
-}
-
-fn tokenize(input: &str) -> Result<Vec<Expression>> {
-    let mut exps = vec![];
 
-    let mut chars = input.chars().into_iter().peekable();
+    /// The nodes of the tree in the order [`Expression::visit`] sees them.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// // The outer group, the sum, 1, the product, 2 and 3.
+    /// assert_eq!(Expression::root("1+2*3").unwrap().iter().count(), 6);
+    /// ```
+    pub fn iter(&self) -> Iter<'_> {
+        Iter { stack: vec![self] }
+    }
 
-    while let Some(char) = chars.next() {
-        if char.is_whitespace() {
-            continue;
-        };
+    /// Rebuilds the tree from the bottom up, replacing every node with what
+    /// `f` returns for it. Children are transformed before their parent, so
+    /// `f` sees a parent with its new children already in place.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let exp = Expression::root("1 + 2 - 3 + 4").unwrap();
+    /// let doubled = exp.clone().transform(&mut |node| match node {
+    ///     Expression::Unit(value) => Expression::number(value.parse::<f64>().unwrap() * 2.0),
+    ///     node => node,
+    /// });
+    ///
+    /// assert_eq!(exp.eval().unwrap(), 4.0);
+    /// assert_eq!(doubled.eval().unwrap(), 8.0);
+    /// assert_eq!(doubled.to_string(), "2 + 4 - 6 + 8");
+    /// ```
+    pub fn transform(mut self, f: &mut impl FnMut(Expression) -> Expression) -> Expression {
+        for child in self.children_mut() {
+            let taken = std::mem::replace(child, Expression::Seq(vec![]));
+            *child = taken.transform(f);
+        }
+        f(self)
+    }
 
-        if char.is_digit(10) {
-            let mut buf = String::new();
-            buf.push(char);
+    /// Replaces every use of the variable `name` with `replacement`. Other
+    /// variables stay as they are, and so does `name` where it is assigned to.
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use nac::Expression;
+    ///
+    /// let square = Expression::root("x^2 + y").unwrap();
+    /// let shifted = square.substitute("x", &Expression::root("t + 1").unwrap());
+    /// assert_eq!(shifted.to_string(), "(t + 1) ^ 2 + y");
+    ///
+    /// let bindings = HashMap::from([("t".to_string(), 2.0), ("y".to_string(), 1.0)]);
+    /// let expanded = Expression::root("t^2 + 2*t + 1 + y").unwrap();
+    /// assert_eq!(shifted.eval_with(&bindings), expanded.eval_with(&bindings));
+    ///
+    /// // Substitutions compose: x becomes 2u, then u becomes t - 1.
+    /// let nested = square
+    ///     .substitute("x", &Expression::mul(Expression::number(2.0), Expression::var("u")))
+    ///     .substitute("u", &Expression::root("t - 1").unwrap());
+    /// let expanded = Expression::root("4*t^2 - 8*t + 4 + y").unwrap();
+    /// assert_eq!(nested.eval_with(&bindings), expanded.eval_with(&bindings));
+    ///
+    /// let assigned = Expression::root("x = x + 1; x").unwrap().substitute("x", &Expression::number(5.0));
+    /// assert_eq!(assigned.to_string(), "x = 5 + 1; 5");
+    /// ```
+    pub fn substitute(&self, name: &str, replacement: &Expression) -> Expression {
+        let mut exp = self.clone();
+        exp.substitute_in(name, replacement);
+        exp
+    }
 
-            while let Some(nxt) = chars.peek() {
-                if nxt.is_digit(10) || nxt == &'.' {
-                    buf.push(chars.next().unwrap())
-                } else {
-                    break;
+    fn substitute_in(&mut self, name: &str, replacement: &Expression) {
+        match self {
+            Expression::Var(var) if var == name => *self = replacement.clone(),
+            Expression::Assign(OpParams { rhs: Some(rhs), .. }) => {
+                rhs.substitute_in(name, replacement)
+            }
+            _ => {
+                for child in self.children_mut() {
+                    child.substitute_in(name, replacement);
                 }
             }
+        }
+    }
 
-            if buf.ends_with('.') {
-                buf.push('0');
-            }
+    /// Differentiates with respect to `var`, using the sum, product and quotient
+    /// rules and the power rule for exponents that don't depend on `var`.
+    /// Anything else that involves `var`, like `x^x` or `sin(x)`, is an error
+    /// rather than a wrong answer. The result is left unsimplified.
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use nac::{CalcError, Expression};
+    ///
+    /// let at = |exp: &Expression, x: f64| exp.eval_with(&HashMap::from([("x".to_string(), x)]));
+    ///
+    /// for source in ["x^2 + 3x", "4 - x/3", "(2x + 1)(x - 5)", "1 / (x^2 + 1)", "x^3 / (x - 7)", "-(x + 2)^-1.5", "5x^0.5 + pi"] {
+    ///     let f = Expression::root(source)?;
+    ///     let slope = f.derivative("x")?;
+    ///     for x in [0.5, 1.0, 2.0, 3.25] {
+    ///         let h = 1e-6;
+    ///         let numeric = (at(&f, x + h)? - at(&f, x - h)?) / (2.0 * h);
+    ///         assert!((at(&slope, x)? - numeric).abs() < 1e-5, "{source} at {x}");
+    ///     }
+    /// }
+    ///
+    /// let tilted = Expression::root("x + 10%")?.derivative("x")?;
+    /// assert_eq!(at(&tilted, 4.0)?, 1.1);
+    ///
+    /// assert_eq!(Expression::root("y^2")?.derivative("x")?.eval()?, 0.0);
+    /// assert!(matches!(
+    ///     Expression::root("x^x")?.derivative("x"),
+    ///     Err(CalcError::NotDifferentiable { .. })
+    /// ));
+    ///
+    /// // `deriv` does the same inside an expression.
+    /// assert_eq!(Expression::root("x = 2; deriv(x^2 + 3x, x)")?.eval()?, 7.0);
+    /// # Ok::<(), CalcError>(())
+    /// ```
+    pub fn derivative(&self, var: &str) -> Result<Expression> {
+        if !self.depends_on(var) {
+            return Ok(Expression::number(0.0));
+        }
 
-            exps.push(Expression::Unit(buf));
-        } else if char == '#' {
-            let mut buf = String::new();
+        let d = |exp: &Expression| exp.derivative(var);
+        let unsupported = || CalcError::NotDifferentiable {
+            expression: self.to_string(),
+            var: var.to_string(),
+        };
 
-            while let Some(nxt) = chars.peek() {
-                if nxt.is_digit(16) {
-                    buf.push(chars.next().unwrap())
-                } else {
+        match self {
+            Expression::Var(_) => Ok(Expression::number(1.0)),
+            Expression::Group(group) => match group.body.as_slice() {
+                [exp] => d(exp),
+                [] => Err(CalcError::EmptyExpression),
+                _ => Err(CalcError::Unresolved { span: None }),
+            },
+            Expression::Neg(Some(inner)) => Ok(Expression::neg(d(inner)?)),
+            Expression::Pos(Some(inner)) => d(inner),
+            Expression::Percent(inner) => Ok(Expression::div(d(inner)?, Expression::number(100.0))),
+            Expression::Add(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            })
+            | Expression::Sub(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            }) => {
+                // `x + 10%` is `x * (1 + 10 / 100)`.
+                if let Expression::Percent(percent) = rhs.as_ref() {
+                    let part = Expression::div(*percent.clone(), Expression::number(100.0));
+                    let factor = match self {
+                        Expression::Add(_) => Expression::add(Expression::number(1.0), part),
+                        _ => Expression::sub(Expression::number(1.0), part),
+                    };
+                    return Expression::mul(*lhs.clone(), factor).derivative(var);
+                }
+                match self {
+                    Expression::Add(_) => Ok(Expression::add(d(lhs)?, d(rhs)?)),
+                    _ => Ok(Expression::sub(d(lhs)?, d(rhs)?)),
+                }
+            }
+            Expression::Mul(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            }) => Ok(Expression::add(
+                Expression::mul(d(lhs)?, *rhs.clone()),
+                Expression::mul(*lhs.clone(), d(rhs)?),
+            )),
+            Expression::Div(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            }) => {
+                if !rhs.depends_on(var) {
+                    return Ok(Expression::div(d(lhs)?, *rhs.clone()));
+                }
+                Ok(Expression::div(
+                    Expression::sub(
+                        Expression::mul(d(lhs)?, *rhs.clone()),
+                        Expression::mul(*lhs.clone(), d(rhs)?),
+                    ),
+                    Expression::pow(*rhs.clone(), Expression::number(2.0)),
+                ))
+            }
+            Expression::Pow(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            }) if !rhs.depends_on(var) => Ok(Expression::mul(
+                Expression::mul(
+                    *rhs.clone(),
+                    Expression::pow(
+                        *lhs.clone(),
+                        Expression::sub(*rhs.clone(), Expression::number(1.0)),
+                    ),
+                ),
+                d(lhs)?,
+            )),
+            _ => Err(unsupported()),
+        }
+    }
+
+    fn depends_on(&self, var: &str) -> bool {
+        self.iter()
+            .any(|exp| matches!(exp, Expression::Var(name) if name == var))
+    }
+
+    /// Folds constant parts of the tree into numbers and drops the operations
+    /// that do nothing: `+ 0`, `- 0`, `* 1`, `/ 1`, `^ 1` and a leading `+`.
+    /// A variable times 0 becomes 0, `0 - x` and `-(-x)` become `-x` and `x`,
+    /// and parentheses go, since the tree already holds the grouping.
+    ///
+    /// Trigonometry is left alone because its result depends on the angle
+    /// mode, and so is anything that fails or overflows, so that evaluating
+    /// the result still reports it. As long as the original evaluates to a
+    /// finite number and its variables hold finite numbers, the simplified
+    /// tree evaluates to the same number.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let simplify = |input| Expression::root(input).unwrap().simplify().to_string();
+    ///
+    /// assert_eq!(simplify("2*3 + x"), "6 + x");
+    /// assert_eq!(simplify("(x + 0) * 1"), "x");
+    /// assert_eq!(simplify("x * 0 + 2^3"), "8");
+    /// assert_eq!(simplify("(x = 2) * 0"), "(x = 2) * 0");
+    /// assert_eq!(simplify("0 - x^1"), "-x");
+    /// assert_eq!(simplify("-(-(y / 1))"), "y");
+    /// assert_eq!(simplify("200 + 10%"), "220");
+    /// assert_eq!(simplify("x + 10%"), "x + 10%");
+    /// assert_eq!(simplify("sin(0) + 2 * 2"), "sin(0) + 4");
+    /// assert_eq!(simplify("(1 // 0) + 1"), "1 // 0 + 1");
+    /// ```
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use nac::Expression;
+    ///
+    /// let atoms = ["0", "1", "2", "3", "0.5", "x", "(x + 0)", "-x", "10%"];
+    /// let ops = [" + ", " - ", " * ", " / ", " ^ "];
+    /// let mut seed: u32 = 7;
+    /// let mut next = |n: usize| {
+    ///     seed ^= seed << 13;
+    ///     seed ^= seed >> 17;
+    ///     seed ^= seed << 5;
+    ///     seed as usize % n
+    /// };
+    ///
+    /// for _ in 0..500 {
+    ///     let mut input = atoms[next(atoms.len())].to_string();
+    ///     for _ in 0..next(5) {
+    ///         input.push_str(ops[next(ops.len())]);
+    ///         input.push_str(atoms[next(atoms.len())]);
+    ///     }
+    ///
+    ///     let exp = Expression::root(&input).unwrap();
+    ///     let env = HashMap::from([("x".to_string(), 3.0)]);
+    ///     let Ok(value) = exp.eval_with_env(&mut env.clone()) else {
+    ///         continue;
+    ///     };
+    ///     if value.is_finite() {
+    ///         assert_eq!(exp.simplify().eval_with_env(&mut env.clone()), Ok(value), "{input}");
+    ///     }
+    /// }
+    /// ```
+    pub fn simplify(&self) -> Expression {
+        self.clone().transform(&mut Expression::fold)
+    }
+
+    fn fold(self) -> Expression {
+        let exp = match self {
+            Expression::Group(mut group)
+                if group.body.len() == 1 && !group.body[0].is_percent() =>
+            {
+                group.body.remove(0)
+            }
+            Expression::Pos(Some(inner)) if !inner.is_percent() => *inner,
+            Expression::Neg(Some(inner)) if matches!(&*inner, Expression::Neg(Some(inner)) if !inner.is_percent()) =>
+            {
+                let Expression::Neg(Some(inner)) = *inner else {
+                    unreachable!()
+                };
+                *inner
+            }
+            Expression::Sub(mut params)
+                if params.lhs.as_ref().is_some_and(|lhs| lhs.is_number(0.0))
+                    && params.rhs.as_ref().is_some_and(|rhs| !rhs.is_percent()) =>
+            {
+                Expression::Neg(params.rhs.take()).fold()
+            }
+            exp => exp,
+        };
+
+        if exp.is_constant() {
+            if let Ok(value) = exp.eval() {
+                if value.is_finite() {
+                    return Expression::number(value);
+                }
+            }
+        }
+
+        let (
+            Some(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            }),
+            Some(op),
+        ) = (exp.params(), exp.op_name())
+        else {
+            return exp;
+        };
+
+        let kept = match op {
+            "add" if lhs.is_number(0.0) => rhs,
+            "add" | "sub" if rhs.is_number(0.0) => lhs,
+            "mul" if lhs.is_number(1.0) => rhs,
+            "mul" | "div" | "pow" if rhs.is_number(1.0) => lhs,
+            "mul"
+                if lhs.is_number(0.0) && matches!(**rhs, Expression::Var(_))
+                    || rhs.is_number(0.0) && matches!(**lhs, Expression::Var(_)) =>
+            {
+                return Expression::number(0.0);
+            }
+            _ => return exp,
+        };
+
+        if kept.is_percent() {
+            return exp;
+        }
+        *kept.clone()
+    }
+
+    fn is_percent(&self) -> bool {
+        matches!(self, Expression::Percent(_))
+    }
+
+    fn is_number(&self, value: f64) -> bool {
+        matches!(self, Expression::Unit(val) if val.parse() == Ok(value))
+    }
+
+    // An operator applied only to numbers, ready to be replaced by its value.
+    // A percentage is kept as an operand, since `200 + 10%` means 220, which
+    // is also why a 0 in front of one can't just be dropped.
+    fn is_constant(&self) -> bool {
+        let operand = |exp: &Expression| match exp {
+            Expression::Unit(_) => true,
+            Expression::Percent(inner) => matches!(**inner, Expression::Unit(_)),
+            _ => false,
+        };
+
+        match self {
+            Expression::Unit(_)
+            | Expression::Var(_)
+            | Expression::Group(_)
+            | Expression::Seq(_)
+            | Expression::Percent(_)
+            | Expression::Assign(_) => false,
+            Expression::Call { name, .. }
+                if matches!(
+                    name.as_str(),
+                    "sin" | "cos" | "tan" | "asin" | "acos" | "atan"
+                ) =>
+            {
+                false
+            }
+            _ => self.children().into_iter().all(operand),
+        }
+    }
+
+    /// The whole number literals that were rounded on their way into an
+    /// `f64`, such as `0x20000000000001`, written in decimal.
+    pub fn inexact_integers(&self) -> Vec<&str> {
+        self.iter()
+            .filter_map(|exp| match exp {
+                Expression::Unit(val) if is_inexact_integer(val) => Some(val.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// How many levels the tree has, counting this node. Parentheses add a
+    /// level each, and so does the group [`Expression::root`] wraps around
+    /// its result.
+    ///
+    /// ```
+    /// use nac::Expression;
+    ///
+    /// let nested = Expression::root("((((1))))").unwrap();
+    /// assert_eq!(nested.depth(), 6);
+    /// assert_eq!(nested.node_count(), 6);
+    ///
+    /// let sum = Expression::root(&vec!["1"; 100].join("+")).unwrap();
+    /// assert_eq!(sum.depth(), 101);
+    /// assert_eq!(sum.node_count(), 200);
+    /// assert_eq!(sum.op_counts()["add"], 99);
+    /// ```
+    pub fn depth(&self) -> usize {
+        1 + self
+            .children()
+            .into_iter()
+            .map(Expression::depth)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// How often each operator appears, keyed by the name of the function
+    /// that builds it (`add`, `neg`, `call`, ...). Numbers, variables, groups
+    /// and statement lists are not operators and are left out.
+    pub fn op_counts(&self) -> HashMap<&'static str, usize> {
+        let mut counts = HashMap::new();
+        for name in self.iter().filter_map(Expression::op_name) {
+            *counts.entry(name).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    fn op_name(&self) -> Option<&'static str> {
+        let name = match self {
+            Expression::Add(_) => "add",
+            Expression::Sub(_) => "sub",
+            Expression::Mul(_) => "mul",
+            Expression::Div(_) => "div",
+            Expression::IntDiv(_) => "int_div",
+            Expression::Mod(_) => "modulo",
+            Expression::Pow(_) => "pow",
+            Expression::Root(_) => "nth_root",
+            Expression::Shl(_) => "shl",
+            Expression::Shr(_) => "shr",
+            Expression::BitAnd(_) => "bit_and",
+            Expression::BitXor(_) => "bit_xor",
+            Expression::BitOr(_) => "bit_or",
+            Expression::Lt(_) => "lt",
+            Expression::Le(_) => "le",
+            Expression::Gt(_) => "gt",
+            Expression::Ge(_) => "ge",
+            Expression::Eq(_) => "equal",
+            Expression::Ne(_) => "not_equal",
+            Expression::And(_) => "and",
+            Expression::Or(_) => "or",
+            Expression::Not(_) => "not",
+            Expression::Assign(_) => "assign",
+            Expression::Neg(_) => "neg",
+            Expression::Pos(_) => "pos",
+            Expression::Fact(_) => "fact",
+            Expression::Abs(_) => "abs",
+            Expression::Percent(_) => "percent",
+            Expression::Call { .. } => "call",
+            Expression::Unit(_)
+            | Expression::Var(_)
+            | Expression::Group(_)
+            | Expression::Seq(_) => return None,
+        };
+
+        Some(name)
+    }
+
+    fn params(&self) -> Option<&OpParams> {
+        match self {
+            Expression::Add(params)
+            | Expression::Sub(params)
+            | Expression::Mul(params)
+            | Expression::Div(params)
+            | Expression::IntDiv(params)
+            | Expression::Mod(params)
+            | Expression::Pow(params)
+            | Expression::Root(params)
+            | Expression::Shl(params)
+            | Expression::Shr(params)
+            | Expression::BitAnd(params)
+            | Expression::BitXor(params)
+            | Expression::BitOr(params)
+            | Expression::Lt(params)
+            | Expression::Le(params)
+            | Expression::Gt(params)
+            | Expression::Ge(params)
+            | Expression::Eq(params)
+            | Expression::Ne(params)
+            | Expression::And(params)
+            | Expression::Or(params)
+            | Expression::Assign(params) => Some(params),
+            _ => None,
+        }
+    }
+
+    fn params_mut(&mut self) -> Option<&mut OpParams> {
+        match self {
+            Expression::Add(params)
+            | Expression::Sub(params)
+            | Expression::Mul(params)
+            | Expression::Div(params)
+            | Expression::IntDiv(params)
+            | Expression::Mod(params)
+            | Expression::Pow(params)
+            | Expression::Root(params)
+            | Expression::Shl(params)
+            | Expression::Shr(params)
+            | Expression::BitAnd(params)
+            | Expression::BitXor(params)
+            | Expression::BitOr(params)
+            | Expression::Lt(params)
+            | Expression::Le(params)
+            | Expression::Gt(params)
+            | Expression::Ge(params)
+            | Expression::Eq(params)
+            | Expression::Ne(params)
+            | Expression::And(params)
+            | Expression::Or(params)
+            | Expression::Assign(params) => Some(params),
+            _ => None,
+        }
+    }
+
+    // The left operand of a left-associative operator, which `evaluate` walks
+    // down in a loop.
+    fn chained_lhs(&self) -> Option<&Expression> {
+        match self.params() {
+            Some(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(_),
+            }) if !self.is_right_associative() => Some(lhs),
+            _ => None,
+        }
+    }
+
+    // The same kind of operator as this one, on `params` instead.
+    fn with_params(&self, params: OpParams) -> Option<Expression> {
+        let exp = match self {
+            Expression::Add(_) => Expression::Add(params),
+            Expression::Sub(_) => Expression::Sub(params),
+            Expression::Mul(_) => Expression::Mul(params),
+            Expression::Div(_) => Expression::Div(params),
+            Expression::IntDiv(_) => Expression::IntDiv(params),
+            Expression::Mod(_) => Expression::Mod(params),
+            Expression::Pow(_) => Expression::Pow(params),
+            Expression::Root(_) => Expression::Root(params),
+            Expression::Shl(_) => Expression::Shl(params),
+            Expression::Shr(_) => Expression::Shr(params),
+            Expression::BitAnd(_) => Expression::BitAnd(params),
+            Expression::BitXor(_) => Expression::BitXor(params),
+            Expression::BitOr(_) => Expression::BitOr(params),
+            Expression::Lt(_) => Expression::Lt(params),
+            Expression::Le(_) => Expression::Le(params),
+            Expression::Gt(_) => Expression::Gt(params),
+            Expression::Ge(_) => Expression::Ge(params),
+            Expression::Eq(_) => Expression::Eq(params),
+            Expression::Ne(_) => Expression::Ne(params),
+            Expression::And(_) => Expression::And(params),
+            Expression::Or(_) => Expression::Or(params),
+            Expression::Assign(_) => Expression::Assign(params),
+            _ => return None,
+        };
+
+        Some(exp)
+    }
+
+    fn is_unresolved_binary(&self) -> bool {
+        self.params()
+            .is_some_and(|params| params.lhs.is_none() && params.rhs.is_none())
+    }
+
+    // Higher binds tighter; see the table above `Group::resolve`.
+    fn precedence(&self) -> u8 {
+        match self {
+            Expression::Unit(value) if value.starts_with('-') => 12,
+            Expression::Assign(_) => 1,
+            Expression::Or(_) => 2,
+            Expression::And(_) => 3,
+            Expression::Not(_) => 4,
+            Expression::Lt(_)
+            | Expression::Le(_)
+            | Expression::Gt(_)
+            | Expression::Ge(_)
+            | Expression::Eq(_)
+            | Expression::Ne(_) => 5,
+            Expression::BitOr(_) => 6,
+            Expression::BitXor(_) => 7,
+            Expression::BitAnd(_) => 8,
+            Expression::Shl(_) | Expression::Shr(_) => 9,
+            Expression::Add(_) | Expression::Sub(_) => 10,
+            Expression::Mul(_)
+            | Expression::Div(_)
+            | Expression::IntDiv(_)
+            | Expression::Mod(_) => 11,
+            Expression::Neg(_)
+            | Expression::Pos(_)
+            | Expression::Root(OpParams {
+                lhs: Some(_),
+                rhs: None,
+            }) => 12,
+            Expression::Pow(_) | Expression::Root(_) => 13,
+            _ => 14,
+        }
+    }
+
+    fn is_right_associative(&self) -> bool {
+        matches!(
+            self,
+            Expression::Pow(_) | Expression::Root(_) | Expression::Assign(_)
+        )
+    }
+
+    fn resolve(&mut self) -> Result<()> {
+        if self.params().is_some() {
+            return self.resolve_operands();
+        }
+
+        match self {
+            Expression::Group(group) => group.resolve(),
+            Expression::Abs(inner)
+            | Expression::Fact(inner)
+            | Expression::Percent(inner)
+            | Expression::Neg(Some(inner))
+            | Expression::Pos(Some(inner))
+            | Expression::Not(Some(inner)) => inner.resolve(),
+            Expression::Call { args, .. } | Expression::Seq(args) => {
+                args.iter_mut().try_for_each(Expression::resolve)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Like `evaluate`, goes down the left of a long run like `1 + 2 + 3` in a
+    // loop. The right operands are resolved afterwards, left to right.
+    fn resolve_operands(&mut self) -> Result<()> {
+        let mut rights = vec![];
+        let mut exp = self;
+
+        while let Some(OpParams { lhs, rhs }) = exp.params_mut() {
+            rights.extend(rhs.as_deref_mut());
+            match lhs.as_deref_mut() {
+                Some(lhs) if lhs.params().is_some() => exp = lhs,
+                Some(lhs) => {
+                    lhs.resolve()?;
                     break;
                 }
+                None => break,
+            }
+        }
+
+        rights.into_iter().rev().try_for_each(Expression::resolve)
+    }
+
+    fn validate(&self) -> Result<()> {
+        match self {
+            Expression::Group(group) => group.validate(),
+            Expression::Abs(inner) | Expression::Fact(inner) | Expression::Percent(inner) => {
+                inner.validate()
+            }
+            Expression::Call { args, .. } | Expression::Seq(args) => {
+                args.iter().try_for_each(Expression::validate)
+            }
+            Expression::Pow(OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            }) => {
+                lhs.validate()?;
+                rhs.validate()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn is_value_prefix(&self) -> bool {
+        matches!(
+            self,
+            Expression::Neg(Some(_)) | Expression::Pos(Some(_)) | Expression::Not(Some(_))
+        )
+    }
+
+    fn is_unresolved_unary(&self) -> bool {
+        matches!(
+            self,
+            Expression::Neg(None)
+                | Expression::Pos(None)
+                | Expression::Root(OpParams {
+                    lhs: Some(_),
+                    rhs: None
+                })
+        )
+    }
+}
+
+/// Builds trees in code rather than from text. Every operator takes all of its
+/// operands up front, so a tree put together this way is always complete.
+///
+/// ```
+/// use nac::Expression;
+///
+/// let n = Expression::number;
+///
+/// let sum = Expression::add(n(1.0), Expression::mul(n(2.0), n(3.0)));
+/// assert_eq!(sum.eval()?, 7.0);
+///
+/// let grouped = Expression::mul(Expression::group(Expression::add(n(1.0), n(2.0))), n(3.0));
+/// assert_eq!(grouped.eval()?, 9.0);
+/// assert_eq!(grouped.to_string(), "(1 + 2) * 3");
+///
+/// let distance = Expression::call(
+///     "sqrt",
+///     vec![Expression::add(
+///         Expression::pow(n(3.0), n(2.0)),
+///         Expression::pow(n(4.0), n(2.0)),
+///     )],
+/// );
+/// assert_eq!(distance.eval()?, 5.0);
+///
+/// let program = Expression::seq(vec![
+///     Expression::assign("x", n(4.0)),
+///     Expression::neg(Expression::fact(Expression::var("x"))),
+/// ]);
+/// assert_eq!(program.eval()?, -24.0);
+/// assert_eq!(Expression::group(program), Expression::root("x = 4; -x!")?);
+/// # Ok::<(), nac::CalcError>(())
+/// ```
+#[allow(clippy::should_implement_trait)]
+impl Expression {
+    pub fn number(value: f64) -> Expression {
+        Expression::Unit(value.to_string())
+    }
+
+    pub fn var(name: impl Into<String>) -> Expression {
+        Expression::Var(name.into())
+    }
+
+    pub fn add(lhs: Expression, rhs: Expression) -> Expression {
+        Expression::Add(OpParams::new(lhs, rhs))
+    }
+
+    pub fn sub(lhs: Expression, rhs: Expression) -> Expression {
+        Expression::Sub(OpParams::new(lhs, rhs))
+    }
+
+    pub fn mul(lhs: Expression, rhs: Expression) -> Expression {
+        Expression::Mul(OpParams::new(lhs, rhs))
+    }
+
+    pub fn div(lhs: Expression, rhs: Expression) -> Expression {
+        Expression::Div(OpParams::new(lhs, rhs))
+    }
+
+    pub fn int_div(lhs: Expression, rhs: Expression) -> Expression {
+        Expression::IntDiv(OpParams::new(lhs, rhs))
+    }
+
+    pub fn modulo(lhs: Expression, rhs: Expression) -> Expression {
+        Expression::Mod(OpParams::new(lhs, rhs))
+    }
+
+    pub fn pow(base: Expression, exponent: Expression) -> Expression {
+        Expression::Pow(OpParams::new(base, exponent))
+    }
+
+    pub fn nth_root(degree: Expression, radicand: Expression) -> Expression {
+        Expression::Root(OpParams::new(degree, radicand))
+    }
+
+    pub fn sqrt(radicand: Expression) -> Expression {
+        Expression::nth_root(Expression::number(2.0), radicand)
+    }
+
+    pub fn shl(lhs: Expression, rhs: Expression) -> Expression {
+        Expression::Shl(OpParams::new(lhs, rhs))
+    }
+
+    pub fn shr(lhs: Expression, rhs: Expression) -> Expression {
+        Expression::Shr(OpParams::new(lhs, rhs))
+    }
+
+    pub fn bit_and(lhs: Expression, rhs: Expression) -> Expression {
+        Expression::BitAnd(OpParams::new(lhs, rhs))
+    }
+
+    pub fn bit_xor(lhs: Expression, rhs: Expression) -> Expression {
+        Expression::BitXor(OpParams::new(lhs, rhs))
+    }
+
+    pub fn bit_or(lhs: Expression, rhs: Expression) -> Expression {
+        Expression::BitOr(OpParams::new(lhs, rhs))
+    }
+
+    pub fn lt(lhs: Expression, rhs: Expression) -> Expression {
+        Expression::Lt(OpParams::new(lhs, rhs))
+    }
+
+    pub fn le(lhs: Expression, rhs: Expression) -> Expression {
+        Expression::Le(OpParams::new(lhs, rhs))
+    }
+
+    pub fn gt(lhs: Expression, rhs: Expression) -> Expression {
+        Expression::Gt(OpParams::new(lhs, rhs))
+    }
+
+    pub fn ge(lhs: Expression, rhs: Expression) -> Expression {
+        Expression::Ge(OpParams::new(lhs, rhs))
+    }
+
+    pub fn equal(lhs: Expression, rhs: Expression) -> Expression {
+        Expression::Eq(OpParams::new(lhs, rhs))
+    }
+
+    pub fn not_equal(lhs: Expression, rhs: Expression) -> Expression {
+        Expression::Ne(OpParams::new(lhs, rhs))
+    }
+
+    pub fn and(lhs: Expression, rhs: Expression) -> Expression {
+        Expression::And(OpParams::new(lhs, rhs))
+    }
+
+    pub fn or(lhs: Expression, rhs: Expression) -> Expression {
+        Expression::Or(OpParams::new(lhs, rhs))
+    }
+
+    pub fn not(operand: Expression) -> Expression {
+        Expression::Not(Some(Box::new(operand)))
+    }
+
+    pub fn assign(name: impl Into<String>, value: Expression) -> Expression {
+        Expression::Assign(OpParams::new(Expression::var(name), value))
+    }
+
+    pub fn neg(operand: Expression) -> Expression {
+        Expression::Neg(Some(Box::new(operand)))
+    }
+
+    pub fn pos(operand: Expression) -> Expression {
+        Expression::Pos(Some(Box::new(operand)))
+    }
+
+    pub fn fact(operand: Expression) -> Expression {
+        Expression::Fact(Box::new(operand))
+    }
+
+    pub fn abs(operand: Expression) -> Expression {
+        Expression::Abs(Box::new(Expression::group(operand)))
+    }
+
+    pub fn percent(operand: Expression) -> Expression {
+        Expression::Percent(Box::new(operand))
+    }
+
+    pub fn call(name: impl Into<String>, args: Vec<Expression>) -> Expression {
+        Expression::Call {
+            name: name.into(),
+            args: args.into_iter().map(Expression::group).collect(),
+        }
+    }
+
+    pub fn group(inner: Expression) -> Expression {
+        Expression::Group(Group {
+            body: vec![inner],
+            spans: vec![Span::default()],
+        })
+    }
+
+    pub fn seq(statements: Vec<Expression>) -> Expression {
+        Expression::Seq(statements.into_iter().map(Expression::group).collect())
+    }
+}
+
+/// Parses through [`Expression::root`].
+///
+/// ```
+/// use nac::Expression;
+///
+/// let e: Expression = "2^10".parse()?;
+/// assert_eq!(e.eval()?, 1024.0);
+///
+/// let err = Expression::try_from("1 +").unwrap_err();
+/// assert_eq!(err.span().unwrap().start, 2);
+/// # Ok::<(), nac::CalcError>(())
+/// ```
+impl FromStr for Expression {
+    type Err = CalcError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        Expression::root(input)
+    }
+}
+
+impl TryFrom<&str> for Expression {
+    type Error = CalcError;
+
+    fn try_from(input: &str) -> Result<Self, Self::Error> {
+        input.parse()
+    }
+}
+
+/// Compares structure, not source text: numbers are equal when their values
+/// are, and positions are ignored.
+///
+/// ```
+/// use nac::Expression;
+///
+/// assert_eq!(Expression::root("1+2").unwrap(), Expression::root("1 + 2.0").unwrap());
+/// assert_ne!(Expression::root("1+2").unwrap(), Expression::root("2+1").unwrap());
+///
+/// let exp = Expression::root("2^(3+1)").unwrap();
+/// assert_eq!(exp.clone().eval().unwrap(), exp.eval().unwrap());
+/// ```
+impl PartialEq for Expression {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Expression::Unit(a), Expression::Unit(b)) => {
+                a == b
+                    || matches!(
+                        (a.parse::<f64>(), b.parse::<f64>()),
+                        (Ok(a), Ok(b)) if a == b
+                    )
+            }
+            (Expression::Var(a), Expression::Var(b)) => a == b,
+            (Expression::Neg(a), Expression::Neg(b))
+            | (Expression::Pos(a), Expression::Pos(b))
+            | (Expression::Not(a), Expression::Not(b)) => a == b,
+            (Expression::Fact(a), Expression::Fact(b))
+            | (Expression::Abs(a), Expression::Abs(b))
+            | (Expression::Percent(a), Expression::Percent(b)) => a == b,
+            (
+                Expression::Call { name, args },
+                Expression::Call {
+                    name: other_name,
+                    args: other_args,
+                },
+            ) => name == other_name && args == other_args,
+            (Expression::Group(a), Expression::Group(b)) => a == b,
+            (Expression::Seq(a), Expression::Seq(b)) => a == b,
+            _ => {
+                std::mem::discriminant(self) == std::mem::discriminant(other)
+                    && self.params().is_some()
+                    && self.params() == other.params()
+            }
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expression::Unit(value) => write!(f, "{value}"),
+            Expression::Var(name) => write!(f, "{name}"),
+            Expression::Group(group) => write!(f, "{group}"),
+            Expression::Seq(statements) => {
+                for (idx, statement) in statements.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{statement}")?;
+                }
+                Ok(())
+            }
+            Expression::Call { name, args } => {
+                write!(f, "{name}(")?;
+                for (idx, arg) in args.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{arg}")?;
+                }
+                write!(f, ")")
+            }
+            Expression::Abs(inner) => {
+                // A bitwise or inside the bars would read as a closing bar.
+                let inner = inner.to_string();
+                if inner.contains(" | ") {
+                    write!(f, "|({inner})|")
+                } else {
+                    write!(f, "|{inner}|")
+                }
+            }
+            Expression::Fact(inner) => {
+                fmt_operand(f, inner, inner.precedence() < self.precedence())?;
+                write!(f, "!")
+            }
+            Expression::Percent(inner) => {
+                fmt_operand(f, inner, inner.precedence() < self.precedence())?;
+                write!(f, "%")
+            }
+            Expression::Neg(Some(operand))
+            | Expression::Pos(Some(operand))
+            | Expression::Not(Some(operand)) => {
+                let symbol = self.symbol().unwrap_or_default();
+                let space = if symbol == "not" { " " } else { "" };
+                write!(f, "{symbol}{space}")?;
+                fmt_operand(f, operand, operand.precedence() < self.precedence())
+            }
+            _ => match self.params() {
+                Some(OpParams {
+                    lhs: Some(lhs),
+                    rhs: Some(rhs),
+                }) => {
+                    let precedence = self.precedence();
+                    let right = self.is_right_associative();
+
+                    let lhs_parens =
+                        lhs.precedence() < precedence || right && lhs.precedence() == precedence;
+                    fmt_operand(f, lhs, lhs_parens)?;
+
+                    write!(f, " {} ", self.symbol().unwrap_or_default())?;
+
+                    // A prefix operator on the right always takes the rest of
+                    // the operand with it, so it never needs parens there.
+                    let rhs_parens = !rhs.is_value_prefix()
+                        && (rhs.precedence() < precedence
+                            || !right && rhs.precedence() == precedence);
+                    fmt_operand(f, rhs, rhs_parens)
+                }
+                _ => write!(f, "{}", self.symbol().unwrap_or_default()),
+            },
+        }
+    }
+}
+
+// Nested groups came from parentheses in the input, so they keep them.
+fn fmt_operand(f: &mut fmt::Formatter, exp: &Expression, parens: bool) -> fmt::Result {
+    if parens || matches!(exp, Expression::Group(_)) {
+        write!(f, "({exp})")
+    } else {
+        write!(f, "{exp}")
+    }
+}
+
+/// The items between a pair of parentheses, or of the whole input. Before
+/// they are put together every operator is checked for its operands, so a
+/// dangling or doubled one is reported where it is, inside parentheses too.
+///
+/// ```
+/// use nac::Expression;
+///
+/// let err = |input| Expression::root(input).unwrap_err().to_string();
+///
+/// assert_eq!(err("1 + (2 * / 3)"), "unexpected / after * at position 9");
+/// assert_eq!(err("1+2)"), "unmatched ) at position 3");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Group {
+    body: Vec<Expression>,
+    spans: Vec<Span>,
+}
+
+/// A pre-order walk over an expression tree, made by [`Expression::iter`].
+pub struct Iter<'a> {
+    stack: Vec<&'a Expression>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a Expression;
+
+    fn next(&mut self) -> Option<&'a Expression> {
+        let exp = self.stack.pop()?;
+        self.stack.extend(exp.children().into_iter().rev());
+        Some(exp)
+    }
+}
+
+impl PartialEq for Group {
+    fn eq(&self, other: &Self) -> bool {
+        self.body == other.body
+    }
+}
+
+impl fmt::Display for Group {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (idx, exp) in self.body.iter().enumerate() {
+            if idx > 0 && !self.body[idx - 1].is_unresolved_unary() {
+                write!(f, " ")?;
+            }
+            fmt_operand(f, exp, false)?;
+        }
+        Ok(())
+    }
+}
+
+impl Group {
+    fn insert(&mut self, idx: usize, exp: Expression, span: Span) {
+        self.body.insert(idx, exp);
+        self.spans.insert(idx, span);
+    }
+
+    // Catches operators with nothing to apply to before resolve starts pulling
+    // the body apart, so the error can still name the operator and where it is.
+    fn validate(&self) -> Result<()> {
+        for (idx, exp) in self.body.iter().enumerate() {
+            exp.validate()?;
+
+            let Some(symbol) = exp.operator_symbol() else {
+                continue;
+            };
+
+            let span = self.spans[idx];
+            let prefix = exp.is_unresolved_prefix();
+
+            if idx == 0 && !prefix {
+                return Err(CalcError::MissingOperand {
+                    op: symbol.to_string(),
+                    side: Some(Side::Left),
+                    span,
+                });
+            }
+
+            match self.body.get(idx + 1) {
+                None if prefix => {
+                    return Err(CalcError::MissingOperand {
+                        op: symbol.to_string(),
+                        side: None,
+                        span,
+                    });
+                }
+                None => {
+                    return Err(CalcError::MissingOperand {
+                        op: symbol.to_string(),
+                        side: Some(Side::Right),
+                        span,
+                    });
+                }
+                Some(next) if !next.is_unresolved_prefix() => {
+                    if let Some(next_symbol) = next.operator_symbol() {
+                        return Err(CalcError::UnexpectedOperator {
+                            op: next_symbol.to_string(),
+                            after: symbol.to_string(),
+                            span: self.spans[idx + 1],
+                        });
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    // Precedence, from tightest to loosest: postfix `!` (applied while
+    // tokenizing), the right-associative `^ ~`, unary `- +`, `* / // %`, `+ -`,
+    // `<< >>`, `&`, `^^`, `|`, then the comparisons `< <= > >= == !=`, the
+    // logical `not`, `and`, `or` and finally the right-associative assignment `=`.
+    // A minus in front of a power negates the whole power, so `-2^2` is -4, but
+    // one right after `^` is read as part of the exponent, so `2^-2` is 0.25.
+    fn resolve(&mut self) -> Result<()> {
+        // Two numbers side by side are almost always a typo, as in `5 4`, so
+        // that is an error. Anything else next to a value is multiplied by
+        // it, which is what makes `2(3 + 4)`, `(1 + 1)(2 + 2)` and `2x` work.
+        let mut idx = 1;
+        while idx < self.body.len() {
+            if let (Expression::Unit(lhs), Expression::Unit(rhs)) =
+                (&self.body[idx - 1], &self.body[idx])
+            {
+                return Err(CalcError::MissingOperator {
+                    lhs: lhs.clone(),
+                    rhs: rhs.clone(),
+                    span: self.spans[idx],
+                });
+            }
+
+            if self.body[idx - 1].is_value() && self.body[idx].is_value() {
+                let at = self.spans[idx].start;
+                self.insert(idx, Expression::Mul(OpParams::default()), Span::new(at, at));
+            }
+            idx += 1;
+        }
+
+        let body = std::mem::take(&mut self.body);
+        let spans = std::mem::take(&mut self.spans);
+        let mut items = body.into_iter().zip(spans).peekable();
+
+        if items.peek().is_some() {
+            let (exp, span) = climb(&mut items, 0)?;
+            self.body.push(exp);
+            self.spans.push(span);
+        }
+
+        if let Some((_, span)) = items.next() {
+            return Err(CalcError::Unresolved { span: Some(span) });
+        }
+
+        match self.body.first_mut() {
+            Some(exp) => exp.resolve(),
+            None => Ok(()),
+        }
+    }
+}
+
+// Builds the tree for operators binding at least as tightly as `min`. A prefix
+// operator takes its operand at its own precedence, or at `min` when that is
+// tighter, so `1 + not 0 + 5` is `(1 + not 0) + 5` rather than `1 + not 5`.
+fn climb(
+    items: &mut Peekable<impl Iterator<Item = (Expression, Span)>>,
+    min: u8,
+) -> Result<(Expression, Span)> {
+    let Some((mut exp, mut span)) = items.next() else {
+        return Err(CalcError::EmptyExpression);
+    };
+    let symbol = exp.symbol().unwrap_or_default();
+
+    if exp.is_unresolved_prefix() {
+        if items.peek().is_none() {
+            return Err(CalcError::MissingOperand {
+                op: symbol.to_string(),
+                side: None,
+                span,
+            });
+        }
+
+        let (operand, operand_span) = climb(items, exp.precedence().max(min))?;
+        span.end = operand_span.end;
+
+        match &mut exp {
+            Expression::Neg(inner) | Expression::Pos(inner) | Expression::Not(inner) => {
+                *inner = Some(Box::new(operand));
+            }
+            Expression::Root(params) => params.rhs = Some(Box::new(operand)),
+            _ => unreachable!("{exp:?} is not a prefix operator"),
+        }
+    } else if exp.is_unresolved_binary() {
+        return Err(CalcError::MissingOperand {
+            op: symbol.to_string(),
+            side: Some(Side::Left),
+            span,
+        });
+    }
+
+    while let Some((next, _)) = items.peek() {
+        let precedence = next.precedence();
+        if !next.is_unresolved_binary() || precedence < min {
+            break;
+        }
+
+        let (mut op, op_span) = items.next().unwrap();
+        let symbol = op.symbol().unwrap_or_default();
+
+        if items.peek().is_none() {
+            return Err(CalcError::MissingOperand {
+                op: symbol.to_string(),
+                side: Some(Side::Right),
+                span: op_span,
+            });
+        }
+
+        let rhs_min = if op.is_right_associative() {
+            precedence
+        } else {
+            precedence + 1
+        };
+        let (rhs, rhs_span) = climb(items, rhs_min)?;
+
+        if let Some(params) = op.params_mut() {
+            params.lhs = Some(Box::new(exp));
+            params.rhs = Some(Box::new(rhs));
+        }
+
+        exp = op;
+        span = Span::new(span.start, rhs_span.end);
+    }
+
+    Ok((exp, span))
+}
+
+/// Parses `input` without resolving it, returning the flat sequence of values
+/// and operators in source order. Operators have no operands attached yet and
+/// parenthesized groups are flat in the same way; several `;`-separated
+/// statements come back as a single `Seq`.
+pub fn tokens(input: &str) -> Result<Vec<Expression>> {
+    Ok(parse(input, MAX_DEPTH)?.body)
+}
+
+// An upper bound on how deeply building, evaluating and dropping the tree
+// `tokens` parse into recurses, worked out without that recursion. Those walk
+// down a run of left-associative operators in a loop, so `1 + 2 + ... + n`
+// adds one level per kind of operator in it however long it is. Any other
+// operator, and a bar, can add a level and start a run of its own under it, so
+// it counts twice. A parenthesis adds however deep its contents go.
+fn nesting(tokens: &[Token]) -> usize {
+    #[derive(Default)]
+    struct Level {
+        nested: usize,
+        runs: u64,
+        inner: usize,
+        deepest: usize,
+        after_operand: bool,
+    }
+
+    impl Level {
+        fn depth(&self) -> usize {
+            // The one is for a group, a value or an implied `*` at the bottom.
+            let depth = 2 * self.nested + self.runs.count_ones() as usize + 1 + self.inner.max(1);
+            self.deepest.max(depth)
+        }
+    }
+
+    let mut levels = vec![Level::default()];
+
+    for token in tokens {
+        let open = levels.len() > 1;
+        let level = levels.last_mut().unwrap();
+
+        match &token.kind {
+            TokenKind::OpenParen => levels.push(Level::default()),
+            TokenKind::CloseParen if open => {
+                let depth = levels.pop().unwrap().depth();
+                let parent = levels.last_mut().unwrap();
+                parent.inner = parent.inner.max(depth);
+                parent.after_operand = true;
+            }
+            TokenKind::Comma | TokenKind::Semicolon => {
+                *level = Level {
+                    deepest: level.depth(),
+                    ..Level::default()
+                };
+            }
+            TokenKind::Operator(op @ (Op::Plus | Op::Minus)) if level.after_operand => {
+                level.runs |= 1 << *op as u32;
+                level.after_operand = false;
+            }
+            TokenKind::Operator(
+                op @ (Op::Star
+                | Op::Slash
+                | Op::DoubleSlash
+                | Op::Shl
+                | Op::Shr
+                | Op::Amp
+                | Op::Xor
+                | Op::Lt
+                | Op::Le
+                | Op::Gt
+                | Op::Ge
+                | Op::EqEq
+                | Op::Ne
+                | Op::And
+                | Op::Or),
+            ) => {
+                level.runs |= 1 << *op as u32;
+                level.after_operand = false;
+            }
+            // `!` and `%` go after their operand, and a bar may close one.
+            TokenKind::Operator(Op::Bang | Op::Percent) | TokenKind::Bar => {
+                level.nested += 1;
+                level.after_operand = true;
+            }
+            TokenKind::Operator(_) => {
+                level.nested += 1;
+                level.after_operand = false;
+            }
+            TokenKind::Superscript(_) => level.nested += 1,
+            _ => level.after_operand = true,
+        }
+    }
+
+    while levels.len() > 1 {
+        let depth = levels.pop().unwrap().depth();
+        let parent = levels.last_mut().unwrap();
+        parent.inner = parent.inner.max(depth);
+    }
+
+    // `root` wraps everything in a group, and statements in a sequence.
+    levels[0].depth() + 2
+}
+
+fn check_parens(tokens: &[Token], errors: &mut Vec<CalcError>) {
+    let mut open = vec![];
+
+    for token in tokens {
+        match token.kind {
+            TokenKind::OpenParen => open.push(token.span),
+            TokenKind::CloseParen if open.pop().is_none() => {
+                errors.push(CalcError::UnmatchedParen { span: token.span });
+            }
+            _ => {}
+        }
+    }
+
+    errors.extend(
+        open.into_iter()
+            .map(|span| CalcError::UnclosedParen { span }),
+    );
+}
+
+fn parse(input: &str, max_depth: usize) -> Result<Group> {
+    let tokens = lex(input)?;
+    if nesting(&tokens) > max_depth {
+        return Err(CalcError::TooDeep { limit: max_depth });
+    }
+
+    let mut tokens = tokens.into_iter().peekable();
+
+    match parse_group(&mut tokens, false)? {
+        (group, None) => Ok(group),
+        (_, Some(stop)) => Err(unexpected(stop)),
+    }
+}
+
+// Parses tokens up to the end of input or the first token that closes
+// something: a `)` or `,` always stops, and so does a `|` that follows a value
+// inside an abs bar.
+fn parse_group(
+    tokens: &mut Peekable<impl Iterator<Item = Token>>,
+    in_bar: bool,
+) -> Result<(Group, Option<Token>)> {
+    let mut statements = vec![];
+    let mut exps = vec![];
+    let mut spans: Vec<Span> = vec![];
+
+    let stop = loop {
+        let Some(Token { kind, mut span }) = tokens.next() else {
+            break None;
+        };
+
+        match kind {
+            TokenKind::CloseParen | TokenKind::Comma => break Some(Token { kind, span }),
+            TokenKind::Bar if in_bar && !expects_operand(&exps) => {
+                break Some(Token { kind, span })
+            }
+            TokenKind::Semicolon => {
+                statements.push(Group {
+                    body: std::mem::take(&mut exps),
+                    spans: std::mem::take(&mut spans),
+                });
+                continue;
+            }
+            TokenKind::Number(literal) => exps.push(Expression::Unit(literal)),
+            TokenKind::HexNumber(val)
+            | TokenKind::BinaryNumber(val)
+            | TokenKind::OctalNumber(val) => exps.push(Expression::Unit(val.to_string())),
+            TokenKind::Ident(name) => {
+                // Anything else calls only with the `(` right after it, so
+                // `x (2)` is still `x * 2`.
+                let function = FUNCTIONS.contains(&name.as_str());
+                let call = tokens.next_if(|next| {
+                    next.kind == TokenKind::OpenParen && (function || next.span.start == span.end)
+                });
+
+                match call {
+                    Some(open) => {
+                        let (args, close) = parse_args(tokens, open.span)?;
+                        span.end = close.end;
+                        exps.push(Expression::Call { name, args });
+                    }
+                    None if function => {
+                        return Err(CalcError::Syntax {
+                            message: format!("{name} needs parentheses, as in {name}(4)"),
+                            span,
+                        });
+                    }
+                    None => exps.push(Expression::Var(name)),
+                }
             }
+            TokenKind::Operator(op) => {
+                let ops = OpParams::default();
 
-            let val = u64::from_str_radix(buf.as_str(), 16)?;
-            exps.push(Expression::Unit(val.to_string()));
-        } else if char == '+' {
-            let ops = OpParams::default();
-            exps.push(Expression::Add(ops));
-        } else if char == '-' {
-            let ops = OpParams::default();
-            exps.push(Expression::Sub(ops));
-        } else if char == '*' {
-            let ops = OpParams::default();
-            exps.push(Expression::Mul(ops));
-        } else if char == '/' {
-            let ops = OpParams::default();
-            exps.push(Expression::Div(ops));
-        } else if char == '%' {
-            let ops = OpParams::default();
-            exps.push(Expression::Mod(ops));
-        } else if char == '^' {
-            let ops = OpParams::default();
-            exps.push(Expression::Pow(ops));
-        } else if char == '~' {
-            let ops = OpParams::default();
-            exps.push(Expression::Root(ops));
-        } else if char == '(' {
-            let mut sc = 0;
-            let mut buf = String::new();
-
-            'parse_paren: loop {
-                let c = chars.next();
-                if c.is_none() {
-                    return Err(anyhow!("someone forgot a )"));
-                }
-
-                let c = c.unwrap();
-
-                if c == ')' {
-                    if sc == 0 {
-                        break 'parse_paren;
-                    } else {
-                        sc -= 1;
+                match op {
+                    Op::Plus if expects_operand(&exps) => exps.push(Expression::Pos(None)),
+                    Op::Plus => exps.push(Expression::Add(ops)),
+                    Op::Minus if expects_operand(&exps) => exps.push(Expression::Neg(None)),
+                    Op::Minus => exps.push(Expression::Sub(ops)),
+                    Op::Star => exps.push(Expression::Mul(ops)),
+                    Op::Slash => exps.push(Expression::Div(ops)),
+                    Op::DoubleSlash => exps.push(Expression::IntDiv(ops)),
+                    Op::Percent
+                        if !expects_operand(&exps)
+                            && ends_operand(
+                                tokens.peek(),
+                                spans.last().is_some_and(|last| last.end == span.start),
+                            ) =>
+                    {
+                        let operand = exps.pop().unwrap();
+                        exps.push(Expression::Percent(Box::new(operand)));
+                    }
+                    Op::Percent => exps.push(Expression::Mod(ops)),
+                    Op::Caret => exps.push(Expression::Pow(ops)),
+                    Op::Root | Op::Tilde if expects_operand(&exps) => {
+                        let ops = OpParams {
+                            lhs: Some(Box::new(Expression::Unit("2".to_string()))),
+                            rhs: None,
+                        };
+                        exps.push(Expression::Root(ops));
+                    }
+                    Op::Tilde | Op::Root => exps.push(Expression::Root(ops)),
+                    Op::Bang => {
+                        if expects_operand(&exps) {
+                            return Err(CalcError::MissingOperand {
+                                op: "!".to_string(),
+                                side: None,
+                                span,
+                            });
+                        }
+
+                        let operand = exps.pop().unwrap();
+                        exps.push(Expression::Fact(Box::new(operand)));
                     }
+                    Op::Shl => exps.push(Expression::Shl(ops)),
+                    Op::Shr => exps.push(Expression::Shr(ops)),
+                    Op::Amp => exps.push(Expression::BitAnd(ops)),
+                    Op::Xor => exps.push(Expression::BitXor(ops)),
+                    Op::Lt => exps.push(Expression::Lt(ops)),
+                    Op::Le => exps.push(Expression::Le(ops)),
+                    Op::Gt => exps.push(Expression::Gt(ops)),
+                    Op::Ge => exps.push(Expression::Ge(ops)),
+                    Op::EqEq => exps.push(Expression::Eq(ops)),
+                    Op::Ne => exps.push(Expression::Ne(ops)),
+                    Op::Assign => exps.push(Expression::Assign(ops)),
+                    Op::And => exps.push(Expression::And(ops)),
+                    Op::Or => exps.push(Expression::Or(ops)),
+                    Op::Not => exps.push(Expression::Not(None)),
+                }
+            }
+            TokenKind::Superscript(exponent) => {
+                if expects_operand(&exps) {
+                    return Err(CalcError::Syntax {
+                        message: "superscript exponent has nothing to raise".to_string(),
+                        span,
+                    });
                 }
 
-                if c == '(' {
-                    sc += 1
-                };
+                let base = exps.pop().unwrap();
+                exps.push(Expression::Pow(OpParams {
+                    lhs: Some(Box::new(base)),
+                    rhs: Some(Box::new(Expression::Unit(exponent))),
+                }));
+            }
+            TokenKind::OpenParen => {
+                let (group, stop) = parse_group(tokens, false)?;
+
+                match stop {
+                    Some(Token {
+                        kind: TokenKind::CloseParen,
+                        span: close,
+                    }) => span.end = close.end,
+                    Some(stop) => return Err(unexpected(stop)),
+                    None => return Err(CalcError::UnclosedParen { span }),
+                }
+
+                exps.push(Expression::Group(group));
+            }
+            TokenKind::Bar
+                if expects_operand(&exps) || opens_after_abs(&exps, span, tokens.peek()) =>
+            {
+                let (group, stop) = parse_group(tokens, true)?;
 
-                buf.push(c);
+                match stop {
+                    Some(Token {
+                        kind: TokenKind::Bar,
+                        span: close,
+                    }) => span.end = close.end,
+                    Some(Token {
+                        kind: TokenKind::Comma,
+                        span,
+                    }) => {
+                        return Err(unexpected(Token {
+                            kind: TokenKind::Comma,
+                            span,
+                        }))
+                    }
+                    _ => return Err(CalcError::UnclosedBar { span }),
+                }
+
+                exps.push(Expression::Abs(Box::new(Expression::Group(group))));
             }
+            TokenKind::Bar => exps.push(Expression::BitOr(OpParams::default())),
+        }
 
-            let body = tokenize(&buf)?;
+        // Postfix operators replace the token they apply to, so they stretch
+        // its span instead of adding one of their own.
+        if exps.len() > spans.len() {
+            spans.push(span);
+        } else if let Some(last) = spans.last_mut() {
+            last.end = span.end;
+        }
+    };
 
-            exps.push(Expression::Group(Group { body }));
-        } else if char == ')' {
-            return Err(anyhow!("sneaky {char}"));
-        } else {
-            return Err(anyhow!("unexpected character {char}"));
+    if statements.is_empty() {
+        return Ok((Group { body: exps, spans }, stop));
+    }
+
+    statements.push(Group { body: exps, spans });
+
+    let statements: Vec<Group> = statements
+        .into_iter()
+        .filter(|group| !group.body.is_empty())
+        .collect();
+
+    let (Some(first), Some(last)) = (statements.first(), statements.last()) else {
+        return Ok((Group::default(), stop));
+    };
+
+    let span = Span::new(first.spans[0].start, last.spans[last.spans.len() - 1].end);
+    let seq = Expression::Seq(statements.into_iter().map(Expression::Group).collect());
+
+    Ok((
+        Group {
+            body: vec![seq],
+            spans: vec![span],
+        },
+        stop,
+    ))
+}
+
+fn parse_args(
+    tokens: &mut Peekable<impl Iterator<Item = Token>>,
+    open: Span,
+) -> Result<(Vec<Expression>, Span)> {
+    if let Some(close) = tokens.next_if(|next| next.kind == TokenKind::CloseParen) {
+        return Ok((vec![], close.span));
+    }
+
+    let mut args = vec![];
+    let mut start = open.end;
+
+    loop {
+        let (group, stop) = parse_group(tokens, false)?;
+        let Some(stop) = stop else {
+            return Err(CalcError::UnclosedParen { span: open });
+        };
+
+        if group.body.is_empty() {
+            return Err(CalcError::Syntax {
+                message: "empty argument".to_string(),
+                span: Span::new(start, stop.span.start),
+            });
+        }
+
+        args.push(Expression::Group(group));
+
+        if stop.kind == TokenKind::CloseParen {
+            return Ok((args, stop.span));
+        }
+
+        start = stop.span.end;
+    }
+}
+
+fn unexpected(token: Token) -> CalcError {
+    let span = token.span;
+    match token.kind {
+        TokenKind::CloseParen => CalcError::UnmatchedParen { span },
+        TokenKind::Comma => CalcError::UnexpectedChar { ch: ',', span },
+        _ => CalcError::Syntax {
+            message: "unexpected token".to_string(),
+            span,
+        },
+    }
+}
+
+// `%` after a value is a percentage when nothing that could be its right hand
+// side follows it, so `50%` and `50% * 2` are percentages and `7 % 3` is mod.
+// A sign could go either way, so it goes by whether the `%` touches its value:
+// `10% + 5` adds to a percentage and `10 % -3` is a mod.
+fn ends_operand(next: Option<&Token>, touching: bool) -> bool {
+    match next.map(|token| &token.kind) {
+        None => true,
+        Some(TokenKind::Operator(Op::Plus | Op::Minus)) => touching,
+        Some(TokenKind::Operator(op)) => !matches!(op, Op::Tilde | Op::Root | Op::Not),
+        Some(kind) => matches!(
+            kind,
+            TokenKind::CloseParen | TokenKind::Bar | TokenKind::Comma | TokenKind::Semicolon
+        ),
+    }
+}
+
+// A literal that is already how `f64` prints its value, like the
+// `1152921504606847000` that 2^60 prints as, reads back as that same value and
+// is left alone.
+fn is_inexact_integer(literal: &str) -> bool {
+    literal.parse::<u128>().is_ok_and(|n| {
+        let stored = n as f64;
+        stored as u128 != n && stored.to_string() != literal
+    })
+}
+
+fn finite(value: f64, options: EvalOptions, expression: impl FnOnce() -> String) -> Result<f64> {
+    if options.check_finite && !value.is_finite() {
+        return Err(CalcError::NonFinite {
+            expression: expression(),
+            value,
+        });
+    }
+
+    Ok(value)
+}
+
+// The names `call_function` knows, and `deriv`, which `evaluate` handles.
+const FUNCTIONS: &[&str] = &[
+    "sqrt", "ln", "log10", "log2", "log", "floor", "ceil", "trunc", "round", "min", "max", "gcd",
+    "lcm", "sin", "cos", "tan", "asin", "acos", "atan", "deriv",
+];
+
+fn call_function(name: &str, args: &[f64], mode: AngleMode) -> Result<f64> {
+    match name {
+        "sqrt" => {
+            let x = single_arg(name, args)?;
+            if x < 0.0 {
+                return Err(CalcError::NoRealResult {
+                    message: format!("sqrt of negative number {x}"),
+                });
+            }
+            Ok(x.sqrt())
+        }
+        "ln" => Ok(positive_arg(name, args)?.ln()),
+        "log10" => Ok(positive_arg(name, args)?.log10()),
+        "log2" => Ok(positive_arg(name, args)?.log2()),
+        "log" => {
+            let (base, x) = two_args(name, args)?;
+            if base <= 0.0 || base == 1.0 {
+                return Err(CalcError::Math {
+                    message: format!("log base must be positive and not 1, got {base}"),
+                });
+            }
+            if x <= 0.0 {
+                return Err(CalcError::Math {
+                    message: format!("log of non-positive number {x}"),
+                });
+            }
+            Ok(x.ln() / base.ln())
+        }
+        "floor" => Ok(single_arg(name, args)?.floor()),
+        "ceil" => Ok(single_arg(name, args)?.ceil()),
+        "trunc" => Ok(single_arg(name, args)?.trunc()),
+        "round" => match args {
+            [x] => Ok(x.round()),
+            [x, digits] => {
+                if digits.fract() != 0.0 {
+                    return Err(CalcError::Math {
+                        message: format!("round expects a whole number of digits, got {digits}"),
+                    });
+                }
+                // Past 2^52 an f64 has no fraction left to round away, and
+                // a factor of 0 means rounding to more than any f64 can hold.
+                let factor = 10f64.powf(*digits);
+                let scaled = x * factor;
+                if !scaled.is_finite() || scaled.abs() >= 2f64.powi(52) {
+                    return Ok(*x);
+                }
+                if factor == 0.0 {
+                    return Ok(0f64.copysign(*x));
+                }
+                Ok(scaled.round() / factor)
+            }
+            _ => Err(CalcError::ArgumentCount {
+                name: name.to_string(),
+                expected: "1 or 2 arguments".to_string(),
+                got: args.len(),
+            }),
+        },
+        "min" | "max" => {
+            let Some((first, rest)) = args.split_first() else {
+                return Err(CalcError::ArgumentCount {
+                    name: name.to_string(),
+                    expected: "at least 1 argument".to_string(),
+                    got: 0,
+                });
+            };
+            let pick = if name == "min" { f64::min } else { f64::max };
+            Ok(rest.iter().copied().fold(*first, pick))
+        }
+        "gcd" => {
+            let (a, b) = two_args(name, args)?;
+            let (a, b) = (to_integer(a, name)?, to_integer(b, name)?);
+            Ok(gcd(a, b) as f64)
+        }
+        "lcm" => {
+            let (a, b) = two_args(name, args)?;
+            let (a, b) = (to_integer(a, name)?, to_integer(b, name)?);
+            if a == 0 || b == 0 {
+                return Err(CalcError::Math {
+                    message: "lcm is undefined for zero".to_string(),
+                });
+            }
+            (a / gcd(a, b))
+                .checked_mul(b)
+                .map(|lcm| lcm.abs() as f64)
+                .ok_or_else(|| CalcError::Math {
+                    message: format!("lcm({a}, {b}) overflows 64 bits"),
+                })
+        }
+        "sin" => Ok(mode.mode_to_radians(single_arg(name, args)?).sin()),
+        "cos" => Ok(mode.mode_to_radians(single_arg(name, args)?).cos()),
+        "tan" => Ok(mode.mode_to_radians(single_arg(name, args)?).tan()),
+        "asin" => Ok(mode.radians_to_mode(single_arg(name, args)?.asin())),
+        "acos" => Ok(mode.radians_to_mode(single_arg(name, args)?.acos())),
+        "atan" => Ok(mode.radians_to_mode(single_arg(name, args)?.atan())),
+        _ => Err(CalcError::UnknownFunction {
+            name: name.to_string(),
+        }),
+    }
+}
+
+fn single_arg(name: &str, args: &[f64]) -> Result<f64> {
+    match args {
+        [x] => Ok(*x),
+        _ => Err(CalcError::ArgumentCount {
+            name: name.to_string(),
+            expected: "1 argument".to_string(),
+            got: args.len(),
+        }),
+    }
+}
+
+fn two_args(name: &str, args: &[f64]) -> Result<(f64, f64)> {
+    match args {
+        [a, b] => Ok((*a, *b)),
+        _ => Err(CalcError::ArgumentCount {
+            name: name.to_string(),
+            expected: "2 arguments".to_string(),
+            got: args.len(),
+        }),
+    }
+}
+
+fn positive_arg(name: &str, args: &[f64]) -> Result<f64> {
+    let x = single_arg(name, args)?;
+    let message = format!("{name} of non-positive number {x}");
+    if x < 0.0 {
+        return Err(CalcError::NoRealResult { message });
+    }
+    if x == 0.0 {
+        return Err(CalcError::Math { message });
+    }
+    Ok(x)
+}
+
+fn gcd(a: i64, b: i64) -> i64 {
+    let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a as i64
+}
+
+fn constant(name: &str) -> Option<f64> {
+    match name.to_ascii_lowercase().as_str() {
+        "pi" => Some(std::f64::consts::PI),
+        "e" => Some(std::f64::consts::E),
+        "tau" => Some(std::f64::consts::TAU),
+        "phi" => Some(1.618_033_988_749_895),
+        "inf" => Some(f64::INFINITY),
+        _ => None,
+    }
+}
+
+fn to_integer(value: f64, op: &str) -> Result<i64> {
+    if value.fract() != 0.0 || value.abs() > i64::MAX as f64 {
+        return Err(CalcError::Math {
+            message: format!("{op} expects integer operands, got {value}"),
+        });
+    }
+
+    Ok(value as i64)
+}
+
+fn to_shift_amount(value: f64, op: &str) -> Result<u32> {
+    let amount = to_integer(value, op)?;
+
+    if !(0..64).contains(&amount) {
+        return Err(CalcError::Math {
+            message: format!("{op} shift amount must be between 0 and 63, got {amount}"),
+        });
+    }
+
+    Ok(amount as u32)
+}
+
+fn factorial(n: f64) -> Result<f64> {
+    if n.fract() != 0.0 {
+        return Ok(gamma(n + 1.0));
+    }
+
+    if n < 0.0 {
+        return Err(CalcError::Math {
+            message: format!("factorial is undefined for negative integer {n}"),
+        });
+    }
+
+    if n > 170.0 {
+        return Ok(f64::INFINITY);
+    }
+
+    Ok((1..=n as u64).map(|k| k as f64).product())
+}
+
+// Lanczos approximation (g = 7, n = 9).
+fn gamma(x: f64) -> f64 {
+    const G: f64 = 7.0;
+    const COEFFS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+
+    if x < 0.5 {
+        return std::f64::consts::PI / ((std::f64::consts::PI * x).sin() * gamma(1.0 - x));
+    }
+
+    let x = x - 1.0;
+    let t = x + G + 0.5;
+    let sum = COEFFS[1..]
+        .iter()
+        .enumerate()
+        .fold(COEFFS[0], |acc, (i, c)| acc + c / (x + i as f64 + 1.0));
+
+    (2.0 * std::f64::consts::PI).sqrt() * t.powf(x + 0.5) * (-t).exp() * sum
+}
+
+// A `|` right after a pair of bars, touching a value that follows, opens
+// another pair instead of being a bitwise or, so `|-3| |4|` is 12 and
+// `|1| | 2` is still 1 | 2. Inside bars the same `|` closes them instead.
+fn opens_after_abs(exps: &[Expression], span: Span, next: Option<&Token>) -> bool {
+    matches!(exps.last(), Some(Expression::Abs(_)))
+        && next.is_some_and(|next| next.span.start == span.end && !ends_operand(Some(next), false))
+}
+
+fn expects_operand(exps: &[Expression]) -> bool {
+    !exps.last().is_some_and(Expression::is_value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn try_eval(input: &str) -> Result<f64> {
+        Expression::root(input)?.eval()
+    }
+
+    fn eval(input: &str) -> f64 {
+        try_eval(input).unwrap_or_else(|err| panic!("{input}: {err}"))
+    }
+
+    fn err(input: &str) -> String {
+        match try_eval(input) {
+            Ok(value) => panic!("{input} gave {value}"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn unary_minus() {
+        for (input, value) in [
+            ("-5", -5.0),
+            ("--5", 5.0),
+            ("2*-3", -6.0),
+            ("(-3+1)", -2.0),
+            ("-(2+3)", -5.0),
+            ("-5 * 3", -15.0),
+            ("2^-3", 0.125),
+            ("-2^2", -4.0),
+        ] {
+            assert_eq!(eval(input), value, "{input}");
+        }
+    }
+
+    #[test]
+    fn factorial() {
+        for (input, value) in [
+            ("0!", 1.0),
+            ("(2+3)!", 120.0),
+            ("3!!", 720.0),
+            ("2^3!", 64.0),
+        ] {
+            assert_eq!(eval(input), value, "{input}");
+        }
+        assert_eq!(
+            err("(-3)!"),
+            "factorial is undefined for negative integer -3"
+        );
+        assert_eq!(
+            err("!5"),
+            "missing operand for ! (factorial goes after its operand, as in 5!) at position 0"
+        );
+    }
+
+    #[test]
+    fn abs_bars() {
+        for (input, value) in [
+            ("|3-5|", 2.0),
+            ("2*|1-4|", 6.0),
+            ("|1-4|^2 + 1", 10.0),
+            ("|2 - |3-5||", 0.0),
+            ("-|2 - 5|", -3.0),
+            ("|-3| |4|", 12.0),
+            ("|2||3|", 6.0),
+            ("|2| |-3| |4|", 24.0),
+            ("||2||3", 6.0),
+            ("|1| | 2", 3.0),
+        ] {
+            assert_eq!(eval(input), value, "{input}");
+        }
+        let render = |input| Expression::root(input).unwrap_err().render(input);
+        assert_eq!(render("|3"), "|3\n^\nunclosed | at position 0");
+        assert_eq!(render("|1| |2"), "|1| |2\n    ^\nunclosed | at position 4");
+        assert_eq!(render("(3"), "(3\n^\nunclosed ( at position 0");
+    }
+
+    #[test]
+    fn floor_division() {
+        for (input, value) in [("7//2", 3.0), ("-7//2", -4.0), ("1 + 7 // 2 * 2", 7.0)] {
+            assert_eq!(eval(input), value, "{input}");
+        }
+        assert_eq!(err("7//0"), "division by zero in '7 // 0'");
+    }
+
+    #[test]
+    fn percent_or_mod() {
+        for (input, value) in [
+            ("50%", 0.5),
+            ("7 % 3", 1.0),
+            ("100 * 15%", 15.0),
+            ("200 + 10%", 220.0),
+            ("200 - 10%", 180.0),
+            ("10% + 5", 5.1),
+            ("50% - 10", -9.5),
+            ("10%-3", -2.9),
+            ("10 % -3", 1.0),
+            ("7 % +3", 1.0),
+        ] {
+            assert_eq!(eval(input), value, "{input}");
+        }
+
+        let sub = Expression::sub(
+            Expression::percent(Expression::number(10.0)),
+            Expression::number(3.0),
+        );
+        assert_eq!(sub.to_string(), "10% - 3");
+        assert_eq!(eval(&sub.to_string()), -2.9);
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        for (input, value) in [
+            ("#ff & #0f", 15.0),
+            ("1 | 2 | 4", 7.0),
+            ("5 ^^ 3", 6.0),
+            ("5 xor 3", 6.0),
+            ("5 ^ 3", 125.0),
+            ("1 | 6 & 3", 3.0),
+        ] {
+            assert_eq!(eval(input), value, "{input}");
+        }
+        assert_eq!(err("6 & 3.5"), "& expects integer operands, got 3.5");
+    }
+
+    #[test]
+    fn shifts() {
+        for (input, value) in [
+            ("1 << 10", 1024.0),
+            ("#ff00 >> 8", 255.0),
+            ("(#f0 >> 4) << (1 + 1)", 60.0),
+            ("1 << 2 + 1", 8.0),
+            ("#ff >> 4 & #3", 3.0),
+        ] {
+            assert_eq!(eval(input), value, "{input}");
+        }
+        assert_eq!(
+            err("1 << 64"),
+            "<< shift amount must be between 0 and 63, got 64"
+        );
+        assert_eq!(
+            err("1 << -1"),
+            "<< shift amount must be between 0 and 63, got -1"
+        );
+    }
+
+    // The right hand side is only evaluated when it can change the answer.
+    #[test]
+    fn logical_operators() {
+        for (input, value) in [
+            ("1 < 2 and 3 > 2", 1.0),
+            ("not 0", 1.0),
+            ("not 5", 0.0),
+            ("1 and 0 or 1", 1.0),
+            ("1 or 1/0", 1.0),
+            ("0 and 1/0", 0.0),
+        ] {
+            assert_eq!(eval(input), value, "{input}");
+        }
+        assert_eq!(err("0 or 1/0"), "division by zero in '1 / 0'");
+    }
+
+    // Positions are byte offsets, so `×` after the two byte `√` is at 5.
+    #[test]
+    fn function_calls() {
+        for (input, value) in [
+            ("sqrt(4)", 2.0),
+            ("sqrt (4)", 2.0),
+            ("2 max (1, 3)", 6.0),
+            ("x = 3; x (2)", 6.0),
+        ] {
+            assert_eq!(eval(input), value, "{input}");
+        }
+        assert_eq!(
+            err("sqrt 4"),
+            "sqrt needs parentheses, as in sqrt(4) at position 0"
+        );
+        assert_eq!(
+            err("1 + ln"),
+            "ln needs parentheses, as in ln(4) at position 4"
+        );
+        assert_eq!(err("foo(1)"), "unknown function: foo");
+
+        for name in &FUNCTIONS[..FUNCTIONS.len() - 1] {
+            let err = call_function(name, &[], AngleMode::Radians).unwrap_err();
+            assert!(!matches!(err, CalcError::UnknownFunction { .. }), "{name}");
+        }
+    }
+
+    #[test]
+    fn error_positions() {
+        assert_eq!(err("* 5"), "missing left hand side for * at position 0");
+        assert_eq!(err("5 +"), "missing right hand side for + at position 2");
+        assert_eq!(
+            err("(1 + 2) * (3 -)"),
+            "missing right hand side for - at position 13"
+        );
+        assert_eq!(err("2 $ 3"), "unexpected character '$' at position 2");
+        assert_eq!(err("√4 ×"), "missing right hand side for * at position 5");
+    }
+
+    #[test]
+    fn implicit_multiplication() {
+        for (input, value) in [
+            ("2(3)", 6.0),
+            ("(1)(2)", 2.0),
+            ("(1 + 1)(2 + 2)", 8.0),
+            ("(2) 3", 6.0),
+            ("x = 3; 2x", 6.0),
+            ("2 sqrt(9) + 1", 7.0),
+        ] {
+            assert_eq!(eval(input), value, "{input}");
+        }
+        let err = Expression::root("5 4").unwrap_err();
+        assert!(matches!(err, CalcError::MissingOperator { .. }));
+        assert_eq!(
+            err.to_string(),
+            "missing operator between '5' and '4' at position 2"
+        );
+    }
+
+    #[test]
+    fn dangling_operators() {
+        assert_eq!(eval("2 * -3"), -6.0);
+        assert_eq!(err("* 5"), "missing left hand side for * at position 0");
+        assert_eq!(err("5 +"), "missing right hand side for + at position 2");
+        assert_eq!(err("2 + * 3"), "unexpected * after + at position 4");
+        assert_eq!(err("(* 5)"), "missing left hand side for * at position 1");
+        assert_eq!(err("(5 +)"), "missing right hand side for + at position 3");
+        assert_eq!(err("1 + (2 * / 3)"), "unexpected / after * at position 9");
+    }
+
+    // A stray `)` is reported where it is, and a missing one at the `(` it
+    // would close.
+    #[test]
+    fn unbalanced_parens() {
+        assert_eq!(err("1+2)"), "unmatched ) at position 3");
+        assert_eq!(err("((1+2)"), "unclosed ( at position 0");
+        assert_eq!(err("(1+(2)"), "unclosed ( at position 0");
+        assert_eq!(err("1 + (2 * (3"), "unclosed ( at position 9");
+    }
+
+    #[test]
+    fn power_associativity() {
+        for (input, value) in [
+            ("2^3^2", 512.0),
+            ("2^2^3", 256.0),
+            ("2^3*2", 16.0),
+            ("2*2^3", 16.0),
+            ("2^3^2/2^3", 64.0),
+            ("16/4/2", 2.0),
+            ("10-4-3", 3.0),
+        ] {
+            assert_eq!(eval(input), value, "{input}");
+        }
+    }
+
+    #[test]
+    fn prefix_root() {
+        for (input, value) in [
+            ("~9", 3.0),
+            ("~~16", 2.0),
+            ("3 + ~16", 7.0),
+            ("(~16)", 4.0),
+            ("2~9", 3.0),
+            ("3~27 * ~4", 6.0),
+            ("2 * ~~16", 4.0),
+        ] {
+            assert_eq!(eval(input), value, "{input}");
+        }
+    }
+
+    // Each line is an input and what it gives with `ans` at 5, or `error`. The
+    // results were first taken from the tree before `resolve` became a
+    // precedence climber.
+    #[test]
+    fn round_to_digits() {
+        for (input, value) in [
+            ("round(2.71828, 2)", 2.72),
+            ("round(1234, -2)", 1200.0),
+            ("round(1, 400)", 1.0),
+            ("round(0.1, 308)", 0.1),
+            ("round(1e300, 20)", 1e300),
+            ("round(1e300, -400)", 0.0),
+            ("round(-5, -400)", -0.0),
+        ] {
+            assert_eq!(eval(input), value, "{input}");
         }
+        assert!(eval("round(-5, -400)").is_sign_negative());
+        assert_eq!(
+            err("round(1, 0.5)"),
+            "round expects a whole number of digits, got 0.5"
+        );
     }
 
-    return Ok(exps);
+    #[test]
+    fn precedence_corpus() {
+        for line in include_str!("../testdata/precedence.txt").lines() {
+            let (input, expected) = line.split_once('\t').unwrap();
+            let mut env = HashMap::from([("ans".to_string(), 5.0)]);
+            let got = match Expression::root(input).and_then(|exp| exp.eval_with_env(&mut env)) {
+                Ok(value) => format!("{value:?}"),
+                Err(_) => "error".to_string(),
+            };
+            assert_eq!(got, expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn half_filled_operator_is_an_error() {
+        let half = |lhs: Option<Expression>, rhs: Option<Expression>| OpParams {
+            lhs: lhs.map(Box::new),
+            rhs: rhs.map(Box::new),
+        };
+        let one = || Some(Expression::number(1.0));
+
+        for params in [half(one(), None), half(None, one()), half(None, None)] {
+            for exp in [
+                Expression::Add(params.clone()),
+                Expression::Pow(params.clone()),
+                Expression::And(params.clone()),
+                Expression::Assign(params.clone()),
+            ] {
+                let err = exp.eval().unwrap_err();
+                assert!(
+                    matches!(
+                        err,
+                        CalcError::Unresolved { .. } | CalcError::MissingOperand { .. }
+                    ),
+                    "{exp:?} gave {err:?}"
+                );
+                assert!(exp.eval_rational().is_err());
+                assert!(exp.eval_complex().is_err());
+            }
+        }
+    }
 }
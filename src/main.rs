@@ -1,69 +1,237 @@
-use std::io::{stdin, stdout, Write};
+use std::collections::HashMap;
 
 use anyhow::Result;
 
-use nac::Expression;
+use nac::{format_result, AngleMode, CalcError, Complex, Expression, Radix};
+
+mod help;
+mod prompt;
+
+use help::help;
+use prompt::Prompt;
 
 fn main() -> Result<()> {
     let mut args = std::env::args().collect::<Vec<String>>();
 
     let _program_name = args.remove(0);
 
+    let mut radix = Radix::Decimal;
+    if let Some(idx) = args.iter().position(|arg| arg == "--hex") {
+        args.remove(idx);
+        radix = Radix::Hex;
+    }
+
+    let mut dot = false;
+    if let Some(idx) = args.iter().position(|arg| arg == "--dot") {
+        args.remove(idx);
+        dot = true;
+    }
+
     if !args.is_empty() {
-        let root = Expression::root(args.join("").as_str());
+        let input = args.join("");
+        let root = Expression::root(&input);
 
         match root {
-            Ok(mut root) => {
-                let res = root.eval();
+            Ok(root) if dot => print!("{}", root.to_dot()),
+            Ok(root) => {
+                warn_inexact(&root);
+                let mut env = HashMap::from([("ans".to_string(), 0.0)]);
+                let res = root.eval_with(&env);
+                let res = match res {
+                    Err(e) => complex_result(&root, e, env.clone(), &mut env, AngleMode::default()),
+                    Ok(res) => Ok(Complex::from(res)),
+                };
 
                 match res {
-                    Ok(res) => {
-                        println!("{}", res);
-                    }
-                    Err(e) => {
-                        eprintln!("{}", e);
-                    }
+                    Ok(value) if !value.is_real() => println!("{value}"),
+                    Ok(value) => print_result(value.re, radix),
+                    Err(e) => report(&input, &e),
                 }
             }
-            Err(e) => {
-                eprintln!("{}", e);
-            }
+            Err(e) => report_parse(&input, &e),
         }
     } else {
-        let mut input = String::new();
+        let mut prompt = Prompt::new()?;
 
         let mut prev_result: f64 = 0.0;
-        loop {
-            input.clear();
-
-            print!("> ");
-            stdout().flush().unwrap();
-            stdin().read_line(&mut input)?;
-            if input.starts_with("q") {
+        let mut env = HashMap::from([("ans".to_string(), prev_result)]);
+        let mut mode = AngleMode::Radians;
+        let mut fractions = false;
+        let mut money = None;
+        while let Some(input) = prompt.read()? {
+            if is_quit(&input) {
                 break;
             }
+
+            match input.trim() {
+                ":deg" => {
+                    mode = AngleMode::Degrees;
+                    continue;
+                }
+                ":rad" => {
+                    mode = AngleMode::Radians;
+                    continue;
+                }
+                ":hex" => {
+                    radix = Radix::Hex;
+                    fractions = false;
+                    money = None;
+                    continue;
+                }
+                ":dec" => {
+                    radix = Radix::Decimal;
+                    fractions = false;
+                    money = None;
+                    continue;
+                }
+                ":frac" => {
+                    radix = Radix::Decimal;
+                    fractions = true;
+                    money = None;
+                    continue;
+                }
+                command if command.starts_with(":money") => {
+                    match command[":money".len()..].trim() {
+                        "" => money = Some(2),
+                        places => match places.parse() {
+                            Ok(places) => money = Some(places),
+                            Err(_) => {
+                                eprintln!("{places} is not a number of places, as in :money 2")
+                            }
+                        },
+                    }
+                    radix = Radix::Decimal;
+                    fractions = false;
+                    continue;
+                }
+                command if command.starts_with(':') => {
+                    match command.strip_prefix(":help") {
+                        Some(topic) if topic.is_empty() || topic.starts_with(' ') => {
+                            println!("{}", help(topic.trim()));
+                        }
+                        _ => eprintln!("unknown command {command}, :help lists the commands"),
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+
             let root = Expression::root_with_prev(input.as_str(), prev_result);
 
             match root {
-                Ok(mut root) => {
-                    let res = root.eval();
+                Ok(root) => {
+                    warn_inexact(&root);
+                    let before = env.clone();
+                    let res = root.eval_with_env_and_mode(&mut env, mode);
+                    let res = match res {
+                        // Starts over from `before`, so nothing the real
+                        // pass assigned on its way to failing counts twice.
+                        Err(e) => complex_result(&root, e, before, &mut env, mode),
+                        Ok(res) => Ok(Complex::from(res)),
+                    };
 
                     match res {
-                        Ok(res) => {
+                        Ok(value) if !value.is_real() => println!("{value}"),
+                        Ok(value) => {
+                            let res = value.re;
                             prev_result = res;
-                            println!("{}", res);
-                        }
-                        Err(e) => {
-                            eprintln!("{}", e);
+                            env.insert("ans".to_string(), res);
+
+                            // Anything without an exact answer, or that uses
+                            // earlier variables, still shows as a decimal.
+                            let exact = fractions.then(|| root.eval_rational().ok());
+                            match (exact.flatten(), money) {
+                                (Some(exact), _) => println!("{exact}"),
+                                (None, Some(places)) => print_money(&root, res, places),
+                                (None, None) => print_result(res, radix),
+                            }
                         }
+                        Err(e) => report(&input, &e),
                     }
                 }
-                Err(e) => {
-                    eprintln!("{}", e);
-                }
+                Err(e) => report_parse(&input, &e),
             }
         }
     }
 
     Ok(())
 }
+
+fn is_quit(input: &str) -> bool {
+    let input = input.trim();
+    ["q", "quit", "exit"]
+        .iter()
+        .any(|command| input.eq_ignore_ascii_case(command))
+}
+
+fn report(input: &str, e: &CalcError) {
+    eprintln!("{}", e.render(input));
+}
+
+// Shows every problem `check` finds in the input, or just `e` when it finds
+// no more than that.
+fn report_parse(input: &str, e: &CalcError) {
+    match Expression::check(input) {
+        Err(errors) if errors.len() > 1 => {
+            for e in errors {
+                report(input, &e);
+            }
+        }
+        _ => report(input, e),
+    }
+}
+
+// Retries input that failed with `error` over the complex numbers, starting
+// from the variables in `start`, when it failed for want of a real answer, as
+// `sqrt(-1)` does, or uses `i`, as `2 + 3i` does. Only a retry that works
+// replaces `env` with what it assigned. Input with `i` in it reports what went
+// wrong over the complex numbers, anything else keeps `error`.
+fn complex_result(
+    root: &Expression,
+    error: CalcError,
+    mut start: HashMap<String, f64>,
+    env: &mut HashMap<String, f64>,
+    mode: AngleMode,
+) -> Result<Complex, CalcError> {
+    let uses_i = root.free_variables().contains(&"i");
+    if !uses_i && !matches!(error, CalcError::NoRealResult { .. }) {
+        return Err(error);
+    }
+
+    let value = root
+        .eval_complex_with_env(&mut start, mode)
+        .map_err(|complex_error| if uses_i { complex_error } else { error })?;
+    *env = start;
+    Ok(value)
+}
+
+fn warn_inexact(root: &Expression) {
+    for literal in root.inexact_integers() {
+        let rounded: f64 = literal.parse().unwrap_or(f64::NAN);
+        eprintln!("warning: {literal} is past 2^53 and is rounded to {rounded}");
+    }
+}
+
+// With the decimal feature the amount is worked out in base 10, so 2.675
+// rounds up to 2.68 instead of down from the float just under it.
+fn print_money(root: &Expression, res: f64, places: usize) {
+    #[cfg(feature = "decimal")]
+    if let Ok(exact) = root.eval_decimal() {
+        println!("{exact:.places$}");
+        return;
+    }
+    #[cfg(not(feature = "decimal"))]
+    let _ = root;
+
+    println!("{res:.places$}");
+}
+
+fn print_result(res: f64, radix: Radix) {
+    let formatted = format_result(res, radix);
+
+    if radix == Radix::Hex && formatted == res.to_string() {
+        eprintln!("warning: {} has no hex form, showing it in decimal", res);
+    }
+
+    println!("{}", formatted);
+}
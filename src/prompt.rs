@@ -0,0 +1,72 @@
+// Reads REPL input a line at a time. `read` gives `None` once the user is done,
+// whether that is end of input, Ctrl-D or Ctrl-C.
+
+#[cfg(feature = "rustyline")]
+pub struct Prompt {
+    editor: rustyline::DefaultEditor,
+    history: Option<std::path::PathBuf>,
+}
+
+#[cfg(feature = "rustyline")]
+impl Prompt {
+    pub fn new() -> anyhow::Result<Prompt> {
+        let mut editor = rustyline::DefaultEditor::new()?;
+        let history =
+            std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".nac_history"));
+
+        if let Some(path) = &history {
+            // There is nothing to load on the first run.
+            let _ = editor.load_history(path);
+        }
+
+        Ok(Prompt { editor, history })
+    }
+
+    pub fn read(&mut self) -> anyhow::Result<Option<String>> {
+        match self.editor.readline("> ") {
+            Ok(line) => {
+                if !line.trim().is_empty() {
+                    self.editor.add_history_entry(line.as_str())?;
+                }
+                Ok(Some(line))
+            }
+            Err(rustyline::error::ReadlineError::Eof)
+            | Err(rustyline::error::ReadlineError::Interrupted) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(feature = "rustyline")]
+impl Drop for Prompt {
+    fn drop(&mut self) {
+        if let Some(path) = &self.history {
+            if let Err(e) = self.editor.save_history(path) {
+                eprintln!("warning: could not save history to {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "rustyline"))]
+pub struct Prompt;
+
+#[cfg(not(feature = "rustyline"))]
+impl Prompt {
+    pub fn new() -> anyhow::Result<Prompt> {
+        Ok(Prompt)
+    }
+
+    pub fn read(&mut self) -> anyhow::Result<Option<String>> {
+        use std::io::{stdin, stdout, Write};
+
+        print!("> ");
+        stdout().flush()?;
+
+        let mut input = String::new();
+        if stdin().read_line(&mut input)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(input))
+    }
+}
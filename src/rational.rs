@@ -0,0 +1,391 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::{constant, to_integer, to_shift_amount, CalcError, Expression, OpParams, Result};
+
+/// An exact fraction, always kept in lowest terms with a positive denominator.
+/// Both parts are `i128`, so results that outgrow that are an error rather
+/// than being rounded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rational {
+    numer: i128,
+    denom: i128,
+}
+
+impl Rational {
+    pub fn new(numer: i128, denom: i128) -> Option<Rational> {
+        if denom == 0 {
+            return None;
+        }
+
+        let divisor = gcd(numer, denom);
+        let (mut numer, mut denom) = (numer / divisor, denom / divisor);
+        if denom < 0 {
+            numer = numer.checked_neg()?;
+            denom = denom.checked_neg()?;
+        }
+
+        Some(Rational { numer, denom })
+    }
+
+    pub fn integer(value: i128) -> Rational {
+        Rational {
+            numer: value,
+            denom: 1,
+        }
+    }
+
+    pub fn numer(&self) -> i128 {
+        self.numer
+    }
+
+    pub fn denom(&self) -> i128 {
+        self.denom
+    }
+
+    pub fn is_integer(&self) -> bool {
+        self.denom == 1
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        self.numer as f64 / self.denom as f64
+    }
+
+    // Reads a literal the way the lexer leaves it: digits with an optional
+    // decimal point and exponent, so `1.25` is 5/4 and `2e-3` is 1/500.
+    fn from_literal(literal: &str) -> Option<Rational> {
+        let (mantissa, exponent) = match literal.split_once(['e', 'E']) {
+            Some((mantissa, exponent)) => (mantissa, exponent.parse::<i32>().ok()?),
+            None => (literal, 0),
+        };
+        let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        if !(whole.chars().chain(fraction.chars())).all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+
+        let numer: i128 = format!("{whole}{fraction}").parse().ok()?;
+        let exponent = exponent.checked_sub(i32::try_from(fraction.len()).ok()?)?;
+        let scale = 10i128.checked_pow(exponent.unsigned_abs())?;
+
+        if exponent < 0 {
+            Rational::new(numer, scale)
+        } else {
+            Some(Rational::integer(numer.checked_mul(scale)?))
+        }
+    }
+
+    fn checked_add(self, other: Rational) -> Option<Rational> {
+        let numer = self
+            .numer
+            .checked_mul(other.denom)?
+            .checked_add(other.numer.checked_mul(self.denom)?)?;
+        Rational::new(numer, self.denom.checked_mul(other.denom)?)
+    }
+
+    fn checked_neg(self) -> Option<Rational> {
+        Some(Rational {
+            numer: self.numer.checked_neg()?,
+            denom: self.denom,
+        })
+    }
+
+    fn checked_sub(self, other: Rational) -> Option<Rational> {
+        self.checked_add(other.checked_neg()?)
+    }
+
+    fn checked_mul(self, other: Rational) -> Option<Rational> {
+        Rational::new(
+            self.numer.checked_mul(other.numer)?,
+            self.denom.checked_mul(other.denom)?,
+        )
+    }
+
+    // `None` on division by zero as well as on overflow, so callers check the
+    // divisor first.
+    fn checked_div(self, other: Rational) -> Option<Rational> {
+        Rational::new(
+            self.numer.checked_mul(other.denom)?,
+            self.denom.checked_mul(other.numer)?,
+        )
+    }
+
+    fn checked_pow(self, exponent: i128) -> Option<Rational> {
+        let base = if exponent < 0 {
+            Rational::new(self.denom, self.numer)?
+        } else {
+            self
+        };
+        let exponent = u32::try_from(exponent.unsigned_abs()).ok()?;
+
+        Some(Rational {
+            numer: base.numer.checked_pow(exponent)?,
+            denom: base.denom.checked_pow(exponent)?,
+        })
+    }
+
+    fn compare(self, other: Rational) -> Option<Ordering> {
+        let lhs = self.numer.checked_mul(other.denom)?;
+        let rhs = other.numer.checked_mul(self.denom)?;
+        Some(lhs.cmp(&rhs))
+    }
+
+    fn floor(self) -> Rational {
+        Rational::integer(self.numer.div_euclid(self.denom))
+    }
+
+    fn ceil(self) -> Rational {
+        Rational::integer(-(-self.numer).div_euclid(self.denom))
+    }
+
+    fn trunc(self) -> Rational {
+        Rational::integer(self.numer / self.denom)
+    }
+}
+
+impl fmt::Display for Rational {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_integer() {
+            write!(f, "{}", self.numer)
+        } else {
+            write!(f, "{}/{}", self.numer, self.denom)
+        }
+    }
+}
+
+fn gcd(a: i128, b: i128) -> i128 {
+    let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    // Only a zero numerator over a zero denominator gives 0, and `new` rules
+    // that out before dividing.
+    a.max(1) as i128
+}
+
+impl Expression {
+    /// Evaluates with exact fractions instead of floats, so `1/3 + 1/3 + 1/3`
+    /// is exactly 1. Decimals are read as the fractions they spell out. Only
+    /// what has an exact answer is allowed: a power needs a whole exponent,
+    /// and roots, trigonometry, logarithms and constants like `pi` are
+    /// [`CalcError::NotExact`]. Numerators and denominators are `i128`, with
+    /// no bigger fractions to move on to, so a result that outgrows them is an
+    /// error.
+    ///
+    /// ```
+    /// use nac::{CalcError, Expression, Rational};
+    ///
+    /// let exact = |input| Expression::root(input).unwrap().eval_rational();
+    ///
+    /// assert_eq!(exact("1/3 + 1/3 + 1/3"), Ok(Rational::integer(1)));
+    /// assert_eq!(exact("0.1 + 0.2"), Ok(Rational::new(3, 10).unwrap()));
+    /// assert_eq!(exact("(2/3)^-2").unwrap().to_string(), "9/4");
+    /// assert_eq!(exact("x = 1/6; x * 3 + 200 + 10%").unwrap().to_string(), "4411/20");
+    /// assert_eq!(exact("7 // 2 + 7 % 2.5").unwrap().to_string(), "5");
+    ///
+    /// assert!(matches!(exact("2^0.5"), Err(CalcError::NotExact { .. })));
+    /// assert!(matches!(exact("2 * pi"), Err(CalcError::NotExact { .. })));
+    /// assert!(matches!(exact("1/(3-3)"), Err(CalcError::DivByZero { .. })));
+    /// assert_eq!(
+    ///     exact("10^20 * 10^20").unwrap_err().to_string(),
+    ///     "10 ^ 20 * 10 ^ 20 overflows 128 bit fractions"
+    /// );
+    /// ```
+    pub fn eval_rational(&self) -> Result<Rational> {
+        let names: Vec<String> = self
+            .free_variables()
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        if !names.is_empty() {
+            return Err(CalcError::UnboundVariables { names });
+        }
+
+        self.evaluate_rational(&mut HashMap::new())
+    }
+
+    fn evaluate_rational(&self, env: &mut HashMap<String, Rational>) -> Result<Rational> {
+        let not_exact = || CalcError::NotExact {
+            expression: self.to_string(),
+        };
+        let overflow = || CalcError::Math {
+            message: format!("{self} overflows 128 bit fractions"),
+        };
+        let div_by_zero = || CalcError::DivByZero {
+            expression: self.to_string(),
+        };
+        let bool = |value: bool| Rational::integer(i128::from(value));
+
+        if let Some(OpParams {
+            lhs: Some(lhs),
+            rhs: Some(rhs),
+        }) = self.params()
+        {
+            if let Expression::Assign(_) = self {
+                let Expression::Var(name) = lhs.as_ref() else {
+                    return Err(CalcError::NotAssignable {
+                        target: lhs.to_string(),
+                    });
+                };
+                let value = rhs.evaluate_rational(env)?;
+                env.insert(name.clone(), value);
+                return Ok(value);
+            }
+
+            let lhs = lhs.evaluate_rational(env)?;
+
+            if let Expression::And(_) | Expression::Or(_) = self {
+                let done = (lhs.numer != 0) == matches!(self, Expression::Or(_));
+                if done {
+                    return Ok(bool(lhs.numer != 0));
+                }
+                return Ok(bool(rhs.evaluate_rational(env)?.numer != 0));
+            }
+
+            // `200 + 10%` adds ten percent of 200.
+            let rhs = match (self, rhs.as_ref()) {
+                (Expression::Add(_) | Expression::Sub(_), Expression::Percent(inner)) => inner
+                    .evaluate_rational(env)?
+                    .checked_mul(lhs)
+                    .and_then(|part| part.checked_div(Rational::integer(100)))
+                    .ok_or_else(overflow)?,
+                _ => rhs.evaluate_rational(env)?,
+            };
+
+            let order = || lhs.compare(rhs).ok_or_else(overflow);
+
+            let value = match self {
+                Expression::Add(_) => lhs.checked_add(rhs),
+                Expression::Sub(_) => lhs.checked_sub(rhs),
+                Expression::Mul(_) => lhs.checked_mul(rhs),
+                Expression::Div(_) | Expression::IntDiv(_) | Expression::Mod(_)
+                    if rhs.numer == 0 =>
+                {
+                    return Err(div_by_zero());
+                }
+                Expression::Div(_) => lhs.checked_div(rhs),
+                Expression::IntDiv(_) => lhs.checked_div(rhs).map(Rational::floor),
+                // Like `%` on floats, the remainder takes the sign of `lhs`.
+                Expression::Mod(_) => lhs
+                    .checked_div(rhs)
+                    .and_then(|quotient| quotient.trunc().checked_mul(rhs))
+                    .and_then(|whole| lhs.checked_sub(whole)),
+                Expression::Pow(_) if !rhs.is_integer() => return Err(not_exact()),
+                Expression::Pow(_) if lhs.numer == 0 && rhs.numer < 0 => {
+                    return Err(div_by_zero());
+                }
+                Expression::Pow(_) => lhs.checked_pow(rhs.numer),
+                Expression::Shl(_)
+                | Expression::Shr(_)
+                | Expression::BitAnd(_)
+                | Expression::BitXor(_)
+                | Expression::BitOr(_) => {
+                    let symbol = self.symbol().unwrap_or_default();
+                    let whole = |value: Rational| match value.is_integer() {
+                        true => Ok(value.numer),
+                        false => to_integer(value.to_f64(), symbol).map(i128::from),
+                    };
+                    let (a, b) = (whole(lhs)?, whole(rhs)?);
+                    let value = match self {
+                        Expression::Shl(_) => {
+                            a.checked_mul(1 << to_shift_amount(b as f64, symbol)?)
+                        }
+                        Expression::Shr(_) => Some(a >> to_shift_amount(b as f64, symbol)?),
+                        Expression::BitAnd(_) => Some(a & b),
+                        Expression::BitXor(_) => Some(a ^ b),
+                        _ => Some(a | b),
+                    };
+                    value.map(Rational::integer)
+                }
+                Expression::Lt(_) => Some(bool(order()?.is_lt())),
+                Expression::Le(_) => Some(bool(order()?.is_le())),
+                Expression::Gt(_) => Some(bool(order()?.is_gt())),
+                Expression::Ge(_) => Some(bool(order()?.is_ge())),
+                Expression::Eq(_) => Some(bool(lhs == rhs)),
+                Expression::Ne(_) => Some(bool(lhs != rhs)),
+                _ => return Err(not_exact()),
+            };
+            return value.ok_or_else(overflow);
+        }
+
+        match self {
+            Expression::Unit(val) => {
+                Rational::from_literal(val).ok_or_else(|| match val.parse::<f64>() {
+                    Ok(value) if value.is_finite() => overflow(),
+                    _ => CalcError::ParseNumber {
+                        literal: val.clone(),
+                    },
+                })
+            }
+            Expression::Var(name) => match env.get(name) {
+                Some(value) => Ok(*value),
+                None if constant(name).is_some() => Err(not_exact()),
+                None => Err(CalcError::UnknownVariable { name: name.clone() }),
+            },
+            Expression::Neg(Some(inner)) => inner
+                .evaluate_rational(env)?
+                .checked_neg()
+                .ok_or_else(overflow),
+            Expression::Pos(Some(inner)) => inner.evaluate_rational(env),
+            Expression::Not(Some(inner)) => Ok(bool(inner.evaluate_rational(env)?.numer == 0)),
+            Expression::Abs(inner) => {
+                let value = inner.evaluate_rational(env)?;
+                match value.numer < 0 {
+                    true => value.checked_neg().ok_or_else(overflow),
+                    false => Ok(value),
+                }
+            }
+            Expression::Percent(inner) => inner
+                .evaluate_rational(env)?
+                .checked_div(Rational::integer(100))
+                .ok_or_else(overflow),
+            Expression::Fact(inner) => {
+                let value = inner.evaluate_rational(env)?;
+                if !value.is_integer() || value.numer < 0 {
+                    return Err(not_exact());
+                }
+                (1..=value.numer)
+                    .try_fold(1i128, |acc, k| acc.checked_mul(k))
+                    .map(Rational::integer)
+                    .ok_or_else(overflow)
+            }
+            Expression::Call { name, args } => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.evaluate_rational(env))
+                    .collect::<Result<Vec<Rational>>>()?;
+                match (name.as_str(), args.as_slice()) {
+                    ("floor", [x]) => Ok(x.floor()),
+                    ("ceil", [x]) => Ok(x.ceil()),
+                    ("trunc", [x]) => Ok(x.trunc()),
+                    ("min" | "max", [first, rest @ ..]) => {
+                        let mut best = *first;
+                        for &arg in rest {
+                            let less = arg.compare(best).ok_or_else(overflow)?.is_lt();
+                            if less == (name == "min") {
+                                best = arg;
+                            }
+                        }
+                        Ok(best)
+                    }
+                    _ => Err(not_exact()),
+                }
+            }
+            Expression::Group(group) => match group.body.as_slice() {
+                [exp] => exp.evaluate_rational(env),
+                [] => Err(CalcError::EmptyExpression),
+                _ => Err(CalcError::Unresolved {
+                    span: Some(group.spans[1]),
+                }),
+            },
+            Expression::Seq(statements) => {
+                let mut value = Rational::integer(0);
+                for statement in statements {
+                    value = statement.evaluate_rational(env)?;
+                }
+                Ok(value)
+            }
+            _ => Err(not_exact()),
+        }
+    }
+}
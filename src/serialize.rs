@@ -0,0 +1,282 @@
+use serde::de::Error as _;
+use serde::ser::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{Expression, Group, OpParams, Span};
+
+// The stored form spells every operand out, so a document can only describe
+// trees `eval` can handle: a number is `{"num": "1.5"}`, a variable is
+// `{"var": "x"}` and everything else is tagged with the name of the function
+// that builds it, as in `{"op": "add", "lhs": ..., "rhs": ...}`.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Node {
+    Num { num: Literal },
+    Var { var: String },
+    Op(Op),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Op {
+    Add(Binary),
+    Sub(Binary),
+    Mul(Binary),
+    Div(Binary),
+    IntDiv(Binary),
+    Modulo(Binary),
+    Pow(Binary),
+    NthRoot(Binary),
+    Shl(Binary),
+    Shr(Binary),
+    BitAnd(Binary),
+    BitXor(Binary),
+    BitOr(Binary),
+    Lt(Binary),
+    Le(Binary),
+    Gt(Binary),
+    Ge(Binary),
+    Equal(Binary),
+    NotEqual(Binary),
+    And(Binary),
+    Or(Binary),
+    Assign(Binary),
+    Not(Unary),
+    Neg(Unary),
+    Pos(Unary),
+    Fact(Unary),
+    Abs(Unary),
+    Percent(Unary),
+    Call { name: String, args: Vec<Node> },
+    Group { body: Box<Node> },
+    Seq { statements: Vec<Node> },
+}
+
+// A number as it was written, so one past what an `f64` holds exactly, like
+// `11e32` or `9007199254740993`, loads back digit for digit. A plain JSON
+// number is read too, as documents stored before this have them.
+struct Literal(String);
+
+impl Serialize for Literal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Literal {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Literal, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Stored {
+            Text(String),
+            Float(f64),
+        }
+
+        match Stored::deserialize(deserializer)? {
+            Stored::Text(text) if text.parse::<f64>().is_ok() => Ok(Literal(text)),
+            Stored::Text(text) => Err(D::Error::custom(format!("invalid number {text}"))),
+            Stored::Float(num) => Ok(Literal(num.to_string())),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Binary {
+    lhs: Box<Node>,
+    rhs: Box<Node>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Unary {
+    operand: Box<Node>,
+}
+
+impl TryFrom<&Expression> for Node {
+    type Error = String;
+
+    fn try_from(exp: &Expression) -> Result<Node, String> {
+        let missing = || format!("{} is missing an operand", exp.label());
+        let binary = |params: &OpParams| match params {
+            OpParams {
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            } => Ok(Binary {
+                lhs: Box::new(Node::try_from(lhs.as_ref())?),
+                rhs: Box::new(Node::try_from(rhs.as_ref())?),
+            }),
+            _ => Err(missing()),
+        };
+        let unary = |operand: Option<&Expression>| match operand {
+            Some(operand) => Ok(Unary {
+                operand: Box::new(Node::try_from(operand)?),
+            }),
+            None => Err(missing()),
+        };
+        let nodes = |exps: &[Expression]| exps.iter().map(Node::try_from).collect::<Result<_, _>>();
+
+        let op = match exp {
+            Expression::Unit(val) => {
+                return match val.parse::<f64>() {
+                    Ok(_) => Ok(Node::Num {
+                        num: Literal(val.clone()),
+                    }),
+                    Err(_) => Err(format!("invalid number {val}")),
+                };
+            }
+            Expression::Var(name) => return Ok(Node::Var { var: name.clone() }),
+            Expression::Add(params) => Op::Add(binary(params)?),
+            Expression::Sub(params) => Op::Sub(binary(params)?),
+            Expression::Mul(params) => Op::Mul(binary(params)?),
+            Expression::Div(params) => Op::Div(binary(params)?),
+            Expression::IntDiv(params) => Op::IntDiv(binary(params)?),
+            Expression::Mod(params) => Op::Modulo(binary(params)?),
+            Expression::Pow(params) => Op::Pow(binary(params)?),
+            Expression::Root(params) => Op::NthRoot(binary(params)?),
+            Expression::Shl(params) => Op::Shl(binary(params)?),
+            Expression::Shr(params) => Op::Shr(binary(params)?),
+            Expression::BitAnd(params) => Op::BitAnd(binary(params)?),
+            Expression::BitXor(params) => Op::BitXor(binary(params)?),
+            Expression::BitOr(params) => Op::BitOr(binary(params)?),
+            Expression::Lt(params) => Op::Lt(binary(params)?),
+            Expression::Le(params) => Op::Le(binary(params)?),
+            Expression::Gt(params) => Op::Gt(binary(params)?),
+            Expression::Ge(params) => Op::Ge(binary(params)?),
+            Expression::Eq(params) => Op::Equal(binary(params)?),
+            Expression::Ne(params) => Op::NotEqual(binary(params)?),
+            Expression::And(params) => Op::And(binary(params)?),
+            Expression::Or(params) => Op::Or(binary(params)?),
+            Expression::Assign(params) => Op::Assign(binary(params)?),
+            Expression::Not(operand) => Op::Not(unary(operand.as_deref())?),
+            Expression::Neg(operand) => Op::Neg(unary(operand.as_deref())?),
+            Expression::Pos(operand) => Op::Pos(unary(operand.as_deref())?),
+            Expression::Fact(operand) => Op::Fact(unary(Some(operand))?),
+            Expression::Abs(operand) => Op::Abs(unary(Some(operand))?),
+            Expression::Percent(operand) => Op::Percent(unary(Some(operand))?),
+            Expression::Call { name, args } => Op::Call {
+                name: name.clone(),
+                args: nodes(args)?,
+            },
+            Expression::Group(group) => Op::Group {
+                body: Box::new(Node::try_from(group)?),
+            },
+            Expression::Seq(statements) => Op::Seq {
+                statements: nodes(statements)?,
+            },
+        };
+
+        Ok(Node::Op(op))
+    }
+}
+
+impl TryFrom<&Group> for Node {
+    type Error = String;
+
+    fn try_from(group: &Group) -> Result<Node, String> {
+        match group.body.as_slice() {
+            [exp] => Node::try_from(exp),
+            [] => Err("empty expression".to_string()),
+            _ => Err("unresolved expression".to_string()),
+        }
+    }
+}
+
+impl From<Binary> for OpParams {
+    fn from(binary: Binary) -> OpParams {
+        OpParams {
+            lhs: Some(Box::new(Expression::from(*binary.lhs))),
+            rhs: Some(Box::new(Expression::from(*binary.rhs))),
+        }
+    }
+}
+
+impl From<Unary> for Box<Expression> {
+    fn from(unary: Unary) -> Box<Expression> {
+        Box::new(Expression::from(*unary.operand))
+    }
+}
+
+impl From<Node> for Expression {
+    fn from(node: Node) -> Expression {
+        let exps = |nodes: Vec<Node>| nodes.into_iter().map(Expression::from).collect();
+
+        let op = match node {
+            Node::Num { num } => return Expression::Unit(num.0),
+            Node::Var { var } => return Expression::Var(var),
+            Node::Op(op) => op,
+        };
+
+        match op {
+            Op::Add(binary) => Expression::Add(binary.into()),
+            Op::Sub(binary) => Expression::Sub(binary.into()),
+            Op::Mul(binary) => Expression::Mul(binary.into()),
+            Op::Div(binary) => Expression::Div(binary.into()),
+            Op::IntDiv(binary) => Expression::IntDiv(binary.into()),
+            Op::Modulo(binary) => Expression::Mod(binary.into()),
+            Op::Pow(binary) => Expression::Pow(binary.into()),
+            Op::NthRoot(binary) => Expression::Root(binary.into()),
+            Op::Shl(binary) => Expression::Shl(binary.into()),
+            Op::Shr(binary) => Expression::Shr(binary.into()),
+            Op::BitAnd(binary) => Expression::BitAnd(binary.into()),
+            Op::BitXor(binary) => Expression::BitXor(binary.into()),
+            Op::BitOr(binary) => Expression::BitOr(binary.into()),
+            Op::Lt(binary) => Expression::Lt(binary.into()),
+            Op::Le(binary) => Expression::Le(binary.into()),
+            Op::Gt(binary) => Expression::Gt(binary.into()),
+            Op::Ge(binary) => Expression::Ge(binary.into()),
+            Op::Equal(binary) => Expression::Eq(binary.into()),
+            Op::NotEqual(binary) => Expression::Ne(binary.into()),
+            Op::And(binary) => Expression::And(binary.into()),
+            Op::Or(binary) => Expression::Or(binary.into()),
+            Op::Assign(binary) => Expression::Assign(binary.into()),
+            Op::Not(unary) => Expression::Not(Some(unary.into())),
+            Op::Neg(unary) => Expression::Neg(Some(unary.into())),
+            Op::Pos(unary) => Expression::Pos(Some(unary.into())),
+            Op::Fact(unary) => Expression::Fact(unary.into()),
+            Op::Abs(unary) => Expression::Abs(unary.into()),
+            Op::Percent(unary) => Expression::Percent(unary.into()),
+            Op::Call { name, args } => Expression::Call {
+                name,
+                args: exps(args),
+            },
+            Op::Group { body } => Expression::Group(Group::from(*body)),
+            Op::Seq { statements } => Expression::Seq(exps(statements)),
+        }
+    }
+}
+
+impl From<Node> for Group {
+    fn from(node: Node) -> Group {
+        Group {
+            body: vec![Expression::from(node)],
+            spans: vec![Span::default()],
+        }
+    }
+}
+
+impl Serialize for Expression {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Node::try_from(self)
+            .map_err(S::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Expression {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Expression, D::Error> {
+        Node::deserialize(deserializer).map(Expression::from)
+    }
+}
+
+impl Serialize for Group {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Node::try_from(self)
+            .map_err(S::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Group {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Group, D::Error> {
+        Node::deserialize(deserializer).map(Group::from)
+    }
+}